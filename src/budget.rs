@@ -0,0 +1,167 @@
+//! Query budgets, to catch N+1 query patterns in tests and staging.
+//!
+//! A budget counts the queries and operations run with
+//! [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl) and
+//! [`AsyncConnection`](crate::AsyncConnection) while a future runs. It is
+//! kept by the task, so queries run by tasks spawned within the scope are
+//! not counted. An inner scope replaces the budget of the outer one until it
+//! ends.
+
+use crate::fingerprint::fingerprint;
+use std::{cell::RefCell, collections::HashMap, fmt, future::Future};
+
+/// Runs `future` with a budget of `max_queries`, logging a warning once it
+/// is exceeded. See [`Budget`] for more limits.
+pub async fn scope<F>(max_queries: usize, future: F) -> F::Output
+where
+    F: Future,
+{
+    Budget::new(max_queries).scope(future).await
+}
+
+/// Limits on the queries run within a [`scope`](Budget::scope).
+///
+/// Going over budget logs a warning with the `log` crate, under the target
+/// `tokio_diesel::budget`, unless the budget is [enforced](Budget::enforce).
+#[derive(Debug, Clone)]
+pub struct Budget {
+    max_queries: usize,
+    max_repeats: Option<usize>,
+    enforce: bool,
+}
+
+impl Budget {
+    /// A budget of `max_queries` queries in total.
+    pub fn new(max_queries: usize) -> Self {
+        Budget {
+            max_queries,
+            max_repeats: None,
+            enforce: false,
+        }
+    }
+
+    /// Also limits how many times the same statement may run. Statements
+    /// are compared by fingerprint, ignoring their literals, bind parameters
+    /// and the length of their `IN` lists, so the same query loading another
+    /// row counts as a repeat. Closures passed to `run` or `transaction` have
+    /// no statement and only count towards the total.
+    pub fn max_repeats(mut self, max_repeats: usize) -> Self {
+        self.max_repeats = Some(max_repeats);
+        self
+    }
+
+    /// Fails queries over budget with [`AsyncError::OverBudget`](crate::AsyncError::OverBudget)
+    /// before they run, instead of logging a warning.
+    pub fn enforce(mut self) -> Self {
+        self.enforce = true;
+        self
+    }
+
+    /// Runs `future` within this budget.
+    pub async fn scope<F>(self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let tally = Tally {
+            budget: self,
+            queries: 0,
+            statements: HashMap::new(),
+        };
+
+        TALLY.scope(RefCell::new(tally), future).await
+    }
+}
+
+/// The limit of a [`Budget`] a query went over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Exceeded {
+    /// More than `max` queries were run.
+    Queries { max: usize },
+
+    /// Statements with the fingerprint `statement` were run more than `max`
+    /// times.
+    Repeats { statement: String, max: usize },
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Exceeded::Queries { max } => write!(f, "more than {} queries", max),
+            Exceeded::Repeats { ref statement, max } => {
+                write!(f, "`{}` run more than {} times", statement, max)
+            }
+        }
+    }
+}
+
+// The queries counted so far in a scope
+struct Tally {
+    budget: Budget,
+    queries: usize,
+    statements: HashMap<String, usize>,
+}
+
+tokio::task_local! {
+    static TALLY: RefCell<Tally>;
+}
+
+impl Tally {
+    fn charge(&mut self, statement: Option<&str>) -> Result<(), Exceeded> {
+        self.queries += 1;
+
+        let mut exceeded = Vec::new();
+
+        // Each limit is logged once, when it is first crossed
+        let enforce = self.budget.enforce;
+        let report = |count: usize, max: usize| {
+            if enforce {
+                count > max
+            } else {
+                count == max + 1
+            }
+        };
+
+        if report(self.queries, self.budget.max_queries) {
+            exceeded.push(Exceeded::Queries {
+                max: self.budget.max_queries,
+            });
+        }
+
+        if let (Some(max), Some(statement)) = (self.budget.max_repeats, statement) {
+            let statement = fingerprint(statement);
+            let count = self.statements.entry(statement.clone()).or_insert(0);
+            *count += 1;
+
+            if report(*count, max) {
+                exceeded.push(Exceeded::Repeats { statement, max });
+            }
+        }
+
+        if self.budget.enforce {
+            return match exceeded.into_iter().next() {
+                Some(exceeded) => Err(exceeded),
+                None => Ok(()),
+            };
+        }
+
+        for exceeded in exceeded {
+            log::warn!(target: "tokio_diesel::budget", "over the query budget: {}", exceeded);
+        }
+
+        Ok(())
+    }
+}
+
+// Counts an operation against the budget of the current task, if any.
+// `statement` is its SQL if known.
+pub(crate) fn charge(statement: Option<&str>) -> Result<(), Exceeded> {
+    TALLY
+        .try_with(|tally| tally.borrow_mut().charge(statement))
+        .unwrap_or(Ok(()))
+}
+
+// Whether the current task has a budget, which needs the SQL of queries
+pub(crate) fn active() -> bool {
+    TALLY.try_with(|_| ()).is_ok()
+}
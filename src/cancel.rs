@@ -0,0 +1,275 @@
+use crate::{manager::Managed, sidecar::Sidecar, AsyncError, AsyncResult};
+use diesel::{
+    r2d2::{self, ConnectionManager},
+    result::QueryResult,
+    Connection,
+};
+use std::sync::{Arc, Condvar, Mutex};
+
+// Cancel requests waiting to be sent before further ones are dropped
+const QUEUE: usize = 16;
+
+/// Connections whose running statement can be cancelled from another
+/// connection. Used by [`AsyncPoolBuilder::cancel_on_drop`](crate::AsyncPoolBuilder::cancel_on_drop).
+///
+/// SQLite is not supported as Diesel does not expose the handle needed to
+/// call `sqlite3_interrupt`.
+pub trait CancelQuery: Connection {
+    /// Identifies the session of this connection, e.g. its backend PID.
+    fn session_id(&self) -> QueryResult<i64>;
+
+    /// Cancels the statement running in the session `session_id`. This is
+    /// called on a new connection, never on the one being cancelled.
+    fn cancel_query(&self, session_id: i64) -> QueryResult<()>;
+}
+
+#[cfg(feature = "postgres")]
+impl CancelQuery for diesel::pg::PgConnection {
+    fn session_id(&self) -> QueryResult<i64> {
+        use diesel::{dsl::sql, sql_types::Integer, RunQueryDsl};
+
+        diesel::select(sql::<Integer>("pg_backend_pid()"))
+            .get_result::<i32>(self)
+            .map(i64::from)
+    }
+
+    fn cancel_query(&self, session_id: i64) -> QueryResult<()> {
+        use diesel::{
+            dsl::sql,
+            sql_types::{BigInt, Bool},
+            RunQueryDsl,
+        };
+
+        diesel::select(
+            sql::<Bool>("pg_cancel_backend(CAST(")
+                .bind::<BigInt, _>(session_id)
+                .sql(" AS integer))"),
+        )
+        .get_result::<bool>(self)
+        .map(|_| ())
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl CancelQuery for diesel::mysql::MysqlConnection {
+    fn session_id(&self) -> QueryResult<i64> {
+        use diesel::{
+            dsl::sql,
+            sql_types::{BigInt, Unsigned},
+            RunQueryDsl,
+        };
+
+        diesel::select(sql::<Unsigned<BigInt>>("CONNECTION_ID()"))
+            .get_result::<u64>(self)
+            .map(|id| id as i64)
+    }
+
+    fn cancel_query(&self, session_id: i64) -> QueryResult<()> {
+        self.execute(&format!("KILL QUERY {}", session_id))
+            .map(|_| ())
+    }
+}
+
+// `CancelQuery` for the connections of one pool
+pub(crate) struct Canceller<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Sends the cancel requests
+    sidecar: Sidecar<Conn>,
+    session_id: fn(&Conn) -> QueryResult<i64>,
+    cancel_query: fn(&Conn, i64) -> QueryResult<()>,
+}
+
+impl<Conn> Canceller<Conn>
+where
+    Conn: 'static + CancelQuery,
+{
+    pub(crate) fn new(manager: Arc<ConnectionManager<Conn>>) -> Self {
+        Canceller {
+            sidecar: Sidecar::new("tokio-diesel-cancel", manager, QUEUE),
+            session_id: Conn::session_id,
+            cancel_query: Conn::cancel_query,
+        }
+    }
+}
+
+enum State {
+    Queued,
+    Running(i64),
+
+    // The future was dropped while the job was running and a cancel request
+    // is being sent. The job waits for it before releasing the connection so
+    // that the request cannot hit a later query.
+    Cancelling,
+
+    // The future was dropped
+    Cancelled,
+
+    Finished,
+}
+
+// Shared by a job and the future waiting on it
+pub(crate) struct Flight<Conn>
+where
+    Conn: 'static + Connection,
+{
+    canceller: Arc<Canceller<Conn>>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl<Conn> Flight<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Run by the sidecar, which then lets the job release its connection
+    fn cancel(&self, conn: Result<&Conn, r2d2::Error>, session_id: i64) -> QueryResult<()> {
+        let result = match conn {
+            Ok(conn) => (self.canceller.cancel_query)(conn, session_id),
+            Err(_) => Ok(()),
+        };
+
+        *self.state.lock().unwrap() = State::Cancelled;
+        self.changed.notify_all();
+
+        result
+    }
+}
+
+// Held by the future waiting on a job. Dropping it before the job finished
+// cancels the job.
+pub(crate) struct Guard<Conn>
+where
+    Conn: 'static + Connection,
+{
+    flight: Option<Arc<Flight<Conn>>>,
+}
+
+impl<Conn> Guard<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) fn new(canceller: Option<&Arc<Canceller<Conn>>>) -> Self {
+        let flight = canceller.map(|canceller| {
+            Arc::new(Flight {
+                canceller: canceller.clone(),
+                state: Mutex::new(State::Queued),
+                changed: Condvar::new(),
+            })
+        });
+
+        Guard { flight }
+    }
+
+    // The half that is moved into the job
+    pub(crate) fn flight(&self) -> Option<Arc<Flight<Conn>>> {
+        self.flight.clone()
+    }
+}
+
+impl<Conn> Drop for Guard<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        let flight = match self.flight {
+            Some(ref flight) => flight,
+            None => return,
+        };
+
+        let mut state = flight.state.lock().unwrap();
+
+        match *state {
+            State::Queued => *state = State::Cancelled,
+
+            // Sending the request blocks and this may be a runtime thread. If
+            // too many are already waiting the statement is left to finish.
+            State::Running(session_id) => {
+                let job = flight.clone();
+                let queued = flight
+                    .canceller
+                    .sidecar
+                    .submit(move |conn| job.cancel(conn, session_id));
+
+                *state = if queued {
+                    State::Cancelling
+                } else {
+                    State::Cancelled
+                };
+            }
+
+            _ => {}
+        }
+    }
+}
+
+// Marks the job finished once `f` returns or panics
+struct Finish<'a, Conn>
+where
+    Conn: 'static + Connection,
+{
+    flight: &'a Flight<Conn>,
+    conn: &'a Managed<Conn>,
+}
+
+impl<'a, Conn> Drop for Finish<'a, Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        let mut state = self.flight.state.lock().unwrap();
+
+        while let State::Cancelling = *state {
+            state = self.flight.changed.wait(state).unwrap();
+        }
+
+        let cancelled = matches!(*state, State::Cancelled);
+        *state = State::Finished;
+        drop(state);
+
+        // Don't hand out a connection the cancellation left unusable
+        if cancelled {
+            self.conn.validate();
+        }
+    }
+}
+
+// Runs `f` on the job's thread, unless the future waiting on it is already
+// gone, so that it can be cancelled from the future
+pub(crate) fn run<Conn, R, F>(
+    flight: Option<Arc<Flight<Conn>>>,
+    conn: &Managed<Conn>,
+    f: F,
+) -> AsyncResult<R>
+where
+    Conn: 'static + Connection,
+    F: FnOnce(&Managed<Conn>) -> AsyncResult<R>,
+{
+    let flight = match flight {
+        Some(flight) => flight,
+        None => return f(conn),
+    };
+
+    let session_id = conn
+        .session_id(flight.canceller.session_id)
+        .map_err(AsyncError::Error)?;
+
+    {
+        let mut state = flight.state.lock().unwrap();
+
+        // Nobody is waiting for the result
+        if let State::Cancelled = *state {
+            return Err(AsyncError::Cancelled);
+        }
+
+        *state = State::Running(session_id);
+    }
+
+    let _finish = Finish {
+        flight: &flight,
+        conn,
+    };
+
+    f(conn)
+}
@@ -0,0 +1,57 @@
+// Classifies database errors.
+//
+// Diesel 1.4 does not expose the SQLSTATE or error number of a failure. It
+// maps the unique (PostgreSQL 23505, MySQL 1062, SQLITE_CONSTRAINT_UNIQUE)
+// and foreign key (23503, 1451/1452, SQLITE_CONSTRAINT_FOREIGNKEY) codes,
+// PostgreSQL's serialization_failure (40001) and a failure to reach the
+// server to a `DatabaseErrorKind`, which is matched first. Everything else is
+// recognised by the start of the message the server or client library
+// reports for that one code, which only works while those messages are in
+// English (PostgreSQL's `lc_messages`).
+use diesel::result::{DatabaseErrorKind, Error};
+
+// PostgreSQL deadlock_detected (40P01) and lock_not_available (55P03, from
+// `lock_timeout` or `NOWAIT`), MySQL ER_LOCK_DEADLOCK (1213) and
+// ER_LOCK_WAIT_TIMEOUT (1205), and SQLITE_BUSY
+const RETRYABLE: &[&str] = &[
+    "deadlock detected",
+    "canceling statement due to lock timeout",
+    "could not obtain lock on ",
+    "Deadlock found when trying to get lock",
+    "Lock wait timeout exceeded",
+    "database is locked",
+];
+
+// PostgreSQL admin_shutdown (57P01) and libpq losing the server, then MySQL
+// CR_SERVER_GONE_ERROR (2006) and CR_SERVER_LOST (2013)
+const CONNECTION_LOST: &[&str] = &[
+    "terminating connection due to administrator command",
+    "server closed the connection unexpectedly",
+    "no connection to the server",
+    "could not send data to server",
+    "could not receive data from server",
+    "MySQL server has gone away",
+    "Lost connection to MySQL server",
+];
+
+// Errors that are expected to succeed when the transaction is run again
+pub(crate) fn is_retryable(err: &Error) -> bool {
+    match *err {
+        Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => true,
+        Error::DatabaseError(_, ref info) => starts_with_any(info.message(), RETRYABLE),
+        _ => false,
+    }
+}
+
+// Errors after which the connection can no longer be used
+pub(crate) fn is_connection_lost(err: &Error) -> bool {
+    match *err {
+        Error::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _) => true,
+        Error::DatabaseError(_, ref info) => starts_with_any(info.message(), CONNECTION_LOST),
+        _ => false,
+    }
+}
+
+fn starts_with_any(message: &str, prefixes: &[&str]) -> bool {
+    prefixes.iter().any(|prefix| message.starts_with(prefix))
+}
@@ -0,0 +1,161 @@
+use diesel::{
+    backend::Backend,
+    query_builder::{AstPass, Query, QueryFragment, QueryId},
+    result::QueryResult,
+};
+use std::{collections::BTreeMap, fmt, future::Future, sync::Arc};
+
+/// Tags appended to statements as a [sqlcommenter](https://google.github.io/sqlcommenter/)
+/// comment, such as `/*app='billing',route='%2Finvoices'*/`, by pools built
+/// with [`AsyncPoolBuilder::sql_commenter`](crate::AsyncPoolBuilder::sql_commenter).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SqlComment {
+    tags: BTreeMap<String, String>,
+}
+
+tokio::task_local! {
+    static SCOPE: SqlComment;
+}
+
+impl SqlComment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a tag, replacing any previous value of `key`.
+    pub fn tag<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.insert(key, value);
+        self
+    }
+
+    /// Adds a tag, replacing any previous value of `key`.
+    pub fn insert<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.tags.insert(key.into(), value.into());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Runs `future` with these tags added to the comment of every statement
+    /// it issues. Scopes nest, the tags of the inner scope taking precedence.
+    pub async fn scope<F>(self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let mut comment = Self::current();
+        comment.tags.extend(self.tags);
+
+        SCOPE.scope(comment, future).await
+    }
+
+    /// The tags of the enclosing [`scope`](SqlComment::scope), if any.
+    pub fn current() -> Self {
+        SCOPE.try_with(Clone::clone).unwrap_or_default()
+    }
+}
+
+// The sqlcommenter format: sorted keys and values, both URL encoded and the
+// values quoted. The encoding also keeps `*/` from ending the comment early.
+impl fmt::Display for SqlComment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("/*")?;
+
+        for (i, (key, value)) in self.tags.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+
+            write!(f, "{}='{}'", Encoded(key), Encoded(value))?;
+        }
+
+        f.write_str("*/")
+    }
+}
+
+struct Encoded<'a>(&'a str);
+
+impl fmt::Display for Encoded<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    write!(f, "{}", byte as char)?
+                }
+                _ => write!(f, "%{:02X}", byte)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) type Commenter = Arc<dyn Fn(&mut SqlComment) + Send + Sync>;
+
+// The comment for a statement issued on the current task
+pub(crate) fn render(commenter: &Commenter) -> Option<String> {
+    let mut comment = SqlComment::current();
+    commenter(&mut comment);
+
+    if comment.is_empty() {
+        None
+    } else {
+        Some(comment.to_string())
+    }
+}
+
+// `query` followed by `comment`. Wrapping a `Query` keeps its type of rows,
+// which is how a cursor over a commented query is declared.
+pub(crate) struct Commented<T> {
+    query: T,
+    comment: String,
+}
+
+impl<T> Commented<T> {
+    pub(crate) fn new(query: T, comment: String) -> Self {
+        Commented { query, comment }
+    }
+}
+
+impl<T> Query for Commented<T>
+where
+    T: Query,
+{
+    type SqlType = T::SqlType;
+}
+
+impl<T> QueryId for Commented<T> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<DB, T> QueryFragment<DB> for Commented<T>
+where
+    DB: Backend,
+    T: QueryFragment<DB>,
+{
+    fn walk_ast(&self, mut out: AstPass<DB>) -> QueryResult<()> {
+        // Trace ids make nearly every comment unique, which would only fill
+        // the statement cache
+        out.unsafe_to_cache_prepared();
+
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(" ");
+        out.push_sql(&self.comment);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+use crate::{
+    cancel::{self, Canceller},
+    context::{QueryTarget, RunQuery},
+    executor::{self, BlockingExecutor},
+    intercept::OperationKind,
+    manager::{Managed, Manager},
+    options::{BeginTransaction, TransactionOptions},
+    settings::Settings,
+    trace::Span,
+    transaction::AsyncTransaction,
+    worker::Lease,
+    AsyncConnection, AsyncError, AsyncResult, AsyncSimpleConnection, QueryFuture,
+};
+use async_trait::async_trait;
+use diesel::{r2d2::PooledConnection, result::QueryResult, Connection};
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+};
+use tokio::{runtime::Handle, sync::OwnedSemaphorePermit, task};
+
+/// A connection checked out of an [`AsyncPool`](crate::AsyncPool) with
+/// [`get_async`](crate::AsyncPool::get_async).
+///
+/// Every query run through this handle uses the same physical connection, so
+/// session state such as temporary tables, `SET` variables and advisory locks
+/// is kept between calls. The connection is returned to the pool on drop.
+pub struct AsyncPooledConnection<Conn>
+where
+    Conn: 'static + Connection,
+{
+    inner: Inner<Conn>,
+    settings: Arc<Settings<Conn>>,
+}
+
+enum Inner<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // The connection moves to whichever thread the executor runs a job on
+    Owned(Arc<dyn BlockingExecutor>, Arc<Owned<Conn>>),
+
+    // The connection lives on a dedicated worker thread
+    Leased(Arc<Lease<Conn>>),
+}
+
+// Shared with in-flight jobs so that the connection (and its pool permit) is
+// only released once the last job using it has finished
+struct Owned<Conn>
+where
+    Conn: 'static + Connection,
+{
+    conn: Mutex<PooledConnection<Manager<Conn>>>,
+    _permit: OwnedSemaphorePermit,
+    canceller: Option<Arc<Canceller<Conn>>>,
+}
+
+// A job queued by `spawn`, which marks the connection broken if it is
+// dropped without having run
+struct Deferred<Conn, F>
+where
+    Conn: 'static + Connection,
+    F: FnOnce(&Managed<Conn>),
+{
+    owned: Arc<Owned<Conn>>,
+    f: Option<F>,
+}
+
+impl<Conn, F> Deferred<Conn, F>
+where
+    Conn: 'static + Connection,
+    F: FnOnce(&Managed<Conn>),
+{
+    fn run(mut self) {
+        let f = self.f.take().expect("job only runs once");
+        let conn = self.owned.conn.lock().unwrap();
+        let _ = conn.catch_unwind(|| {
+            f(&conn);
+            Ok(())
+        });
+    }
+}
+
+impl<Conn, F> Drop for Deferred<Conn, F>
+where
+    Conn: 'static + Connection,
+    F: FnOnce(&Managed<Conn>),
+{
+    fn drop(&mut self) {
+        if self.f.is_some() {
+            if let Ok(conn) = self.owned.conn.lock() {
+                conn.mark_broken();
+            }
+        }
+    }
+}
+
+impl<Conn> AsyncPooledConnection<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) fn new(
+        executor: Arc<dyn BlockingExecutor>,
+        conn: PooledConnection<Manager<Conn>>,
+        permit: OwnedSemaphorePermit,
+        canceller: Option<Arc<Canceller<Conn>>>,
+        settings: Arc<Settings<Conn>>,
+    ) -> Self {
+        let owned = Owned {
+            conn: Mutex::new(conn),
+            _permit: permit,
+            canceller,
+        };
+
+        AsyncPooledConnection {
+            inner: Inner::Owned(executor, Arc::new(owned)),
+            settings,
+        }
+    }
+
+    pub(crate) fn leased(lease: Arc<Lease<Conn>>, settings: Arc<Settings<Conn>>) -> Self {
+        AsyncPooledConnection {
+            inner: Inner::Leased(lease),
+            settings,
+        }
+    }
+
+    /// Starts a transaction on this connection. The connection is returned
+    /// to the pool once the transaction is committed, rolled back or dropped.
+    pub async fn begin(self) -> AsyncResult<AsyncTransaction<Conn>> {
+        AsyncTransaction::begin(Arc::new(self)).await
+    }
+
+    /// Starts a transaction on this connection with the given options.
+    pub async fn begin_with(
+        self,
+        options: TransactionOptions,
+    ) -> AsyncResult<AsyncTransaction<Conn>>
+    where
+        Conn: BeginTransaction,
+    {
+        AsyncTransaction::begin_with(Arc::new(self), options).await
+    }
+
+    // Runs `f` against the held connection without waiting for it to finish.
+    // Used from `Drop` implementations where there is nothing to await, so
+    // this must not panic: outside a runtime, where `SpawnBlocking` would,
+    // `f` is not run and the connection is marked broken for the pool to
+    // discard it. The same happens if the executor panics or drops the job,
+    // as `spawn_blocking` does while the runtime shuts down.
+    pub(crate) fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce(&Managed<Conn>) + Send + 'static,
+    {
+        match self.inner {
+            Inner::Owned(ref executor, ref owned) => {
+                let job = Deferred {
+                    owned: owned.clone(),
+                    f: Some(f),
+                };
+
+                if Handle::try_current().is_ok() {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                        executor.execute(Box::new(move || job.run()))
+                    }));
+                }
+            }
+
+            Inner::Leased(ref lease) => lease.spawn(f),
+        }
+    }
+
+    // Instruments an operation of `AsyncConnection`
+    fn span(&self, operation: &'static str, kind: OperationKind, sql: Option<&str>) -> Span {
+        Span::new::<Conn::Backend>(operation, sql)
+            .pool(&self.settings.name, || None)
+            .intercept(self.settings.intercept(kind, sql, None))
+    }
+
+    // Runs `f` against the held connection
+    pub(crate) async fn with_conn<R, F>(&self, f: F) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Managed<Conn>) -> AsyncResult<R> + Send + 'static,
+    {
+        match self.inner {
+            Inner::Owned(ref executor, ref owned) => {
+                let guard = cancel::Guard::new(owned.canceller.as_ref());
+                let flight = guard.flight();
+                let owned = owned.clone();
+
+                executor::run(&**executor, move || {
+                    let conn = owned.conn.lock().unwrap();
+                    conn.catch_unwind(|| cancel::run(flight, &conn, f))
+                })
+                .await
+            }
+
+            Inner::Leased(ref lease) => lease.run(f).await,
+        }
+    }
+
+    // Runs `f` against the held connection on the calling thread, so that it
+    // may borrow
+    fn with_conn_here<R, F>(&self, f: F) -> AsyncResult<R>
+    where
+        F: FnOnce(&Conn) -> AsyncResult<R>,
+    {
+        match self.inner {
+            Inner::Owned(_, ref owned) => executor::run_here(|| {
+                let conn = owned.conn.lock().unwrap();
+                conn.catch_unwind(|| f(&conn))
+            }),
+
+            Inner::Leased(ref lease) => lease.run_here(f),
+        }
+    }
+}
+
+impl<Conn> fmt::Debug for AsyncPooledConnection<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncPooledConnection").finish()
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for AsyncPooledConnection<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> AsyncResult<()> {
+        let span = self.span("batch_execute", OperationKind::BatchExecute, Some(query));
+        let query = query.to_string();
+
+        span.run(|timer| {
+            self.with_conn(move |conn| {
+                timer.time(|| conn.batch_execute(&query).map_err(AsyncError::Error))
+            })
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for AsyncPooledConnection<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send,
+    {
+        self.span("run", OperationKind::Run, None)
+            .run(|timer| async move {
+                self.with_conn_here(|conn| timer.time(|| f(conn).map_err(AsyncError::Error)))
+            })
+            .await
+    }
+
+    #[inline]
+    async fn transaction<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send,
+    {
+        self.span("transaction", OperationKind::Transaction, None)
+            .run(|timer| async move {
+                self.with_conn_here(|conn| {
+                    timer.time(|| conn.transaction(|| f(conn)).map_err(AsyncError::Error))
+                })
+            })
+            .await
+    }
+
+    #[inline]
+    async fn run_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.span("run", OperationKind::Run, None)
+            .run(|timer| {
+                self.with_conn(move |conn| timer.time(|| f(conn).map_err(AsyncError::Error)))
+            })
+            .await
+    }
+
+    #[inline]
+    async fn transaction_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.span("transaction", OperationKind::Transaction, None)
+            .run(|timer| {
+                self.with_conn(move |conn| {
+                    timer.time(|| conn.transaction(|| f(conn)).map_err(AsyncError::Error))
+                })
+            })
+            .await
+    }
+}
+
+impl<Conn> QueryTarget<Conn> for AsyncPooledConnection<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn settings(&self) -> Option<&Settings<Conn>> {
+        Some(&self.settings)
+    }
+
+    fn pool_state(&self) -> Option<r2d2::State> {
+        None
+    }
+
+    // Never runs on the configured executor as it may run jobs on the
+    // calling thread, which would then block on a consumer that never runs
+    fn run_detached<'a, F>(&'a self, f: F) -> QueryFuture<'a, ()>
+    where
+        F: FnOnce(&Conn) + Send + 'static,
+    {
+        match self.inner {
+            Inner::Owned(_, ref owned) => {
+                let owned = owned.clone();
+                task::spawn_blocking(move || {
+                    let conn = owned.conn.lock().unwrap();
+                    let _ = conn.catch_unwind(|| {
+                        f(&conn);
+                        Ok(())
+                    });
+                });
+            }
+
+            Inner::Leased(ref lease) => lease.spawn(move |conn| f(conn)),
+        }
+
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl<Conn, T, R> RunQuery<Conn, T, R> for AsyncPooledConnection<Conn>
+where
+    Conn: 'static + Connection,
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    fn run_query<'a, F>(&'a self, query: T, f: F) -> QueryFuture<'a, R>
+    where
+        T: 'a,
+        R: 'a,
+        F: FnOnce(T, &Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.run_owned(move |conn| f(query, conn))
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{
+    budget,
+    intercept::OperationKind,
+    settings::Settings,
+    trace::{Rows, Span, Timer},
+    AsyncResult, QueryFuture,
+};
+use diesel::{backend::Backend, query_builder::QueryFragment, result::QueryResult, Connection};
+use std::{fmt, future::Future, panic::Location, time::Duration};
+
+#[cfg(feature = "query-context")]
+use crate::AsyncError;
+
+/// Details of a failed query run with [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl),
+/// available from [`AsyncError::context`](crate::AsyncError::context).
+///
+/// Only attached with the `query-context` feature enabled, which wraps the
+/// errors of such queries in [`AsyncError::Query`](crate::AsyncError::Query)
+/// and includes the context when they are displayed.
+#[derive(Debug, Clone)]
+pub struct QueryContext {
+    sql: Option<String>,
+    elapsed: Duration,
+    pool_wait: Option<Duration>,
+    location: &'static Location<'static>,
+}
+
+impl QueryContext {
+    /// The SQL of the query, without the values of its bind parameters.
+    /// For a table loaded as a whole this is just the table name.
+    pub fn sql(&self) -> Option<&str> {
+        self.sql.as_deref()
+    }
+
+    /// The time from the call until the query failed.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The time spent waiting for a connection, or `None` if the query
+    /// never got one.
+    pub fn pool_wait(&self) -> Option<Duration> {
+        self.pool_wait
+    }
+
+    /// Where the query was run from.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+}
+
+impl fmt::Display for QueryContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref sql) = self.sql {
+            write!(f, "query `{}` ", sql)?;
+        }
+
+        write!(f, "at {} failed after {:?}", self.location, self.elapsed)?;
+
+        if let Some(pool_wait) = self.pool_wait {
+            write!(f, ", {:?} of which waiting for a connection", pool_wait)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Leaves out the binds, which may hold sensitive values
+fn render<DB, T>(query: &T) -> Option<String>
+where
+    DB: Backend,
+    DB::QueryBuilder: Default,
+    T: QueryFragment<DB>,
+{
+    use diesel::query_builder::QueryBuilder;
+
+    let mut out = DB::QueryBuilder::default();
+    query.to_sql(&mut out).ok()?;
+
+    Some(out.finish())
+}
+
+/// How [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl) runs a query of type
+/// `T` returning `R` on a type of connection, to bound generic code over the
+/// r2d2 `Pool`, [`AsyncPool`](crate::AsyncPool) and its connections and
+/// transactions. Queries and their results must be `'static` as they move to
+/// another thread.
+///
+/// Sealed: it is only implemented by the connection types of this crate.
+pub trait RunQuery<Conn, T, R>: QueryTarget<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[doc(hidden)]
+    fn run_query<'a, F>(&'a self, query: T, f: F) -> QueryFuture<'a, R>
+    where
+        T: 'a,
+        R: 'a,
+        F: FnOnce(T, &Conn) -> QueryResult<R> + Send + 'static;
+}
+
+/// The rest of what [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl) needs from
+/// a type of connection, whatever the query.
+///
+/// Sealed like [`RunQuery`].
+pub trait QueryTarget<Conn>: sealed::Sealed
+where
+    Conn: 'static + Connection,
+{
+    // The settings of the pool the connection belongs to, which apply to the
+    // queries run with `AsyncRunQueryDsl`. Plain r2d2 pools have none.
+    #[doc(hidden)]
+    fn settings(&self) -> Option<&Settings<Conn>>;
+
+    // The size of the pool for its metrics, `None` for a checked out
+    // connection
+    #[doc(hidden)]
+    fn pool_state(&self) -> Option<r2d2::State>;
+
+    // Runs `f` on a thread of its own without waiting for it to finish.
+    // Returns once a connection has been checked out for it.
+    #[doc(hidden)]
+    fn run_detached<'a, F>(&'a self, f: F) -> QueryFuture<'a, ()>
+    where
+        F: FnOnce(&Conn) + Send + 'static;
+}
+
+// Keeps `QueryTarget`, and so `RunQuery`, from being implemented elsewhere
+mod sealed {
+    use crate::{AsyncPool, AsyncPooledConnection, AsyncTransaction};
+    use diesel::{
+        r2d2::{ConnectionManager, Pool},
+        Connection,
+    };
+
+    pub trait Sealed {}
+
+    impl<Conn> Sealed for Pool<ConnectionManager<Conn>> where Conn: 'static + Connection {}
+    impl<Conn> Sealed for AsyncPool<Conn> where Conn: 'static + Connection {}
+    impl<Conn> Sealed for AsyncPooledConnection<Conn> where Conn: 'static + Connection {}
+    impl<Conn> Sealed for AsyncTransaction<Conn> where Conn: 'static + Connection {}
+}
+
+// Runs `f` with `query` through `asc` in a span, attaching a `QueryContext`
+// to any error with the `query-context` feature
+pub(crate) async fn run<Conn, AsyncConn, T, R, F>(
+    asc: &AsyncConn,
+    query: T,
+    location: &'static Location<'static>,
+    kind: OperationKind,
+    f: F,
+    rows: fn(&R) -> Rows,
+) -> AsyncResult<R>
+where
+    Conn: 'static + Connection,
+    <Conn::Backend as Backend>::QueryBuilder: Default,
+    AsyncConn: RunQuery<Conn, T, R> + Sync + ?Sized,
+    T: QueryFragment<Conn::Backend> + Send,
+    R: Send,
+    F: FnOnce(T, &Conn) -> QueryResult<R> + Send + 'static,
+{
+    let sql = sql(asc, &query);
+    let timer = Timer::new();
+
+    let job = {
+        let timer = timer.clone();
+        asc.run_query(query, move |query, conn| timer.time(|| f(query, conn)))
+    };
+
+    instrument(asc, sql, location, kind, timer, job, rows).await
+}
+
+// The SQL of `query`, if anything run by `instrument` will use it
+pub(crate) fn sql<Conn, AsyncConn, T>(asc: &AsyncConn, query: &T) -> Option<String>
+where
+    Conn: 'static + Connection,
+    <Conn::Backend as Backend>::QueryBuilder: Default,
+    AsyncConn: QueryTarget<Conn> + ?Sized,
+    T: QueryFragment<Conn::Backend>,
+{
+    let settings = asc.settings();
+
+    if cfg!(any(feature = "query-context", feature = "tracing"))
+        || settings.is_some_and(|settings| {
+            settings.slow_query_log.is_some()
+                || !settings.interceptors.is_empty()
+                || settings.query_stats.is_some()
+        })
+        || budget::active()
+    {
+        render::<Conn::Backend, _>(query)
+    } else {
+        None
+    }
+}
+
+// Runs `job`, which runs the query `sql` and times it with `timer`, as an
+// operation of `asc`: in a span, between the hooks of the interceptors,
+// within the query budget and recorded by the slow query log and the query
+// statistics
+pub(crate) async fn instrument<Conn, AsyncConn, R, F>(
+    asc: &AsyncConn,
+    sql: Option<String>,
+    location: &'static Location<'static>,
+    kind: OperationKind,
+    timer: Timer,
+    job: F,
+    rows: fn(&R) -> Rows,
+) -> AsyncResult<R>
+where
+    Conn: 'static + Connection,
+    AsyncConn: QueryTarget<Conn> + ?Sized,
+    F: Future<Output = AsyncResult<R>>,
+{
+    let settings = asc.settings();
+
+    let span = Span::new::<Conn::Backend>("query", sql.as_deref())
+        .kind(kind)
+        .pool(
+            settings.map_or("default", |settings| &settings.name),
+            || asc.pool_state(),
+        )
+        .intercept(
+            settings.and_then(|settings| settings.intercept(kind, sql.as_deref(), Some(location))),
+        );
+
+    let result = span.instrument(&timer, job).await;
+
+    if let Some(slow_query_log) = settings.and_then(|settings| settings.slow_query_log.as_ref()) {
+        slow_query_log.record(sql.as_deref(), timer.elapsed(), location);
+    }
+
+    let query_stats = settings.and_then(|settings| settings.query_stats.as_ref());
+    if let (Some(query_stats), Some(sql)) = (query_stats, sql.as_deref()) {
+        query_stats.record(sql, timer.blocking(), result.is_err());
+    }
+
+    match result {
+        Ok(value) => {
+            span.record_rows(rows(&value));
+            Ok(value)
+        }
+
+        #[cfg(not(feature = "query-context"))]
+        Err(error) => Err(error),
+
+        #[cfg(feature = "query-context")]
+        Err(error) => {
+            let context = QueryContext {
+                sql,
+                elapsed: timer.elapsed(),
+                pool_wait: timer.pool_wait(),
+                location,
+            };
+
+            Err(AsyncError::Query {
+                error: Box::new(error),
+                context: Box::new(context),
+            })
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::stream::Sink;
+use diesel::{
+    pg::Pg,
+    query_builder::{AstPass, Query, QueryFragment, QueryId},
+    query_dsl::RunQueryDsl,
+    result::QueryResult,
+    sql_types::HasSqlType,
+    Connection, Queryable,
+};
+use std::marker::PhantomData;
+
+// A connection is busy for as long as a cursor is being read so one name is
+// enough. The cursor is closed once read so that another can be declared
+// later in the same transaction.
+const CURSOR: &str = "tokio_diesel_cursor";
+
+struct Declare<T>(T);
+
+impl<T> QueryFragment<Pg> for Declare<T>
+where
+    T: QueryFragment<Pg>,
+{
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.push_sql("DECLARE ");
+        out.push_identifier(CURSOR)?;
+        out.push_sql(" NO SCROLL CURSOR FOR ");
+        self.0.walk_ast(out.reborrow())
+    }
+}
+
+impl<T> QueryId for Declare<T> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+// Fetches the next rows of the cursor as rows of `ST`
+struct Fetch<ST> {
+    count: u32,
+    _marker: PhantomData<ST>,
+}
+
+impl<ST> Query for Fetch<ST> {
+    type SqlType = ST;
+}
+
+impl<ST> QueryFragment<Pg> for Fetch<ST> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.push_sql(&format!("FETCH FORWARD {} FROM ", self.count));
+        out.push_identifier(CURSOR)
+    }
+}
+
+impl<ST> QueryId for Fetch<ST> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<ST, Conn> RunQueryDsl<Conn> for Fetch<ST> {}
+
+// Reads `query` through a cursor `batch_size` rows at a time, sending each
+// row to `sink`. Runs in a transaction (or savepoint) as cursors only live
+// as long as the transaction they were declared in.
+pub(crate) fn load<Conn, T, U>(
+    conn: &Conn,
+    query: T,
+    batch_size: u32,
+    sink: &Sink<U>,
+) -> QueryResult<()>
+where
+    Conn: Connection<Backend = Pg>,
+    T: Query + QueryFragment<Pg> + QueryId,
+    Pg: HasSqlType<T::SqlType>,
+    U: Queryable<T::SqlType, Pg>,
+{
+    conn.transaction(|| {
+        conn.execute_returning_count(&Declare(query))?;
+
+        'fetch: loop {
+            let fetch = Fetch::<T::SqlType> {
+                count: batch_size,
+                _marker: PhantomData,
+            };
+
+            let rows: Vec<U> = fetch.load(conn)?;
+            let exhausted = rows.len() < batch_size as usize;
+
+            for row in rows {
+                // The stream was dropped
+                if !sink.send(row) {
+                    break 'fetch;
+                }
+            }
+
+            if exhausted {
+                break;
+            }
+        }
+
+        conn.batch_execute(&format!("CLOSE {}", CURSOR))
+    })
+}
@@ -0,0 +1,170 @@
+use crate::{AsyncError, AsyncResult, PanicPayload};
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    sync::oneshot,
+    task,
+};
+
+/// A unit of blocking work handed to a [`BlockingExecutor`].
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Strategy used to run blocking Diesel work from async code.
+///
+/// The executor only has to run the job (now or later, on any thread); the
+/// job itself delivers its result back to the awaiting task.
+pub trait BlockingExecutor: fmt::Debug + Send + Sync {
+    fn execute(&self, job: Job);
+}
+
+/// Uses `block_in_place` on the multi-threaded scheduler and falls back to
+/// `spawn_blocking` on a `current_thread` runtime. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Auto;
+
+impl BlockingExecutor for Auto {
+    fn execute(&self, job: Job) {
+        match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+            Ok(RuntimeFlavor::CurrentThread) => SpawnBlocking.execute(job),
+            _ => BlockInPlace.execute(job),
+        }
+    }
+}
+
+/// Runs the job on the current worker thread via `block_in_place`. Nothing is
+/// moved between threads but the worker is unavailable to other tasks while
+/// the job runs. Panics on a `current_thread` runtime.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BlockInPlace;
+
+impl BlockingExecutor for BlockInPlace {
+    fn execute(&self, job: Job) {
+        task::block_in_place(job)
+    }
+}
+
+/// Moves the job onto Tokio's blocking thread pool via `spawn_blocking`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SpawnBlocking;
+
+impl BlockingExecutor for SpawnBlocking {
+    fn execute(&self, job: Job) {
+        task::spawn_blocking(job);
+    }
+}
+
+/// Runs the job directly on the calling thread, blocking it. Intended for
+/// tests and tools that do not care about stalling the runtime.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Inline;
+
+impl BlockingExecutor for Inline {
+    fn execute(&self, job: Job) {
+        job()
+    }
+}
+
+/// A dedicated, fixed-size pool of OS threads that is independent of Tokio.
+pub struct ThreadPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+    size: usize,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "thread pool must have at least one thread");
+
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..size {
+            let receiver = receiver.clone();
+
+            thread::Builder::new()
+                .name(format!("tokio-diesel-{}", index))
+                .spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+
+                    match job {
+                        Ok(job) => job(),
+                        // The pool was dropped
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn thread pool worker");
+        }
+
+        ThreadPool {
+            sender: Mutex::new(sender),
+            size,
+        }
+    }
+}
+
+impl fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadPool")
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl BlockingExecutor for ThreadPool {
+    fn execute(&self, job: Job) {
+        // Workers only exit once the sender is dropped so this cannot fail
+        let _ = self.sender.lock().unwrap().send(job);
+    }
+}
+
+// Runs `f` on the calling thread and returns its result. Unlike `run`, `f`
+// may borrow from the caller as it never leaves the thread: it runs through
+// `block_in_place` on the multi-threaded scheduler, so that the worker's other
+// tasks can move elsewhere meanwhile, and inline anywhere else. A panic in
+// `f` is returned as `AsyncError::Panicked`.
+pub(crate) fn run_here<R, F>(f: F) -> AsyncResult<R>
+where
+    F: FnOnce() -> AsyncResult<R>,
+{
+    let f = AssertUnwindSafe(f);
+
+    let result = match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => task::block_in_place(|| panic::catch_unwind(f)),
+        _ => panic::catch_unwind(f),
+    };
+
+    result.unwrap_or_else(|payload| Err(AsyncError::Panicked(PanicPayload::new(payload))))
+}
+
+// Runs `f` on `executor` and waits for its result. A panic in `f` is
+// returned as `AsyncError::Panicked`.
+pub(crate) async fn run<E, R, F>(executor: &E, f: F) -> AsyncResult<R>
+where
+    E: BlockingExecutor + ?Sized,
+    R: Send + 'static,
+    F: FnOnce() -> AsyncResult<R> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let job: Job = Box::new(move || {
+        let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+    });
+
+    // The job catches its own panics so this can only be the executor
+    // refusing to run in the current context
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| executor.execute(job))) {
+        let payload = PanicPayload::new(payload);
+        let message = payload.message().unwrap_or("executor panicked").to_string();
+
+        return Err(AsyncError::Runtime(message));
+    }
+
+    match rx.await {
+        Ok(Ok(result)) => result,
+        Ok(Err(payload)) => Err(AsyncError::Panicked(PanicPayload::new(payload))),
+        Err(_) => Err(AsyncError::Join),
+    }
+}
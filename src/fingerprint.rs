@@ -0,0 +1,175 @@
+// The fingerprint of a statement, shared by the statements that differ only
+// in whitespace, literals, bind parameters or the length of `IN` lists
+pub(crate) fn fingerprint(sql: &str) -> String {
+    collapse_lists(&strip(sql))
+}
+
+// Collapses whitespace and comments and replaces string and number literals
+// and the placeholders of bind parameters with `?`
+fn strip(sql: &str) -> String {
+    let mut stripped = String::with_capacity(sql.len());
+    let mut chars = sql.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // `''` escapes a quote within the literal
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+
+                stripped.push('?');
+            }
+
+            // PostgreSQL placeholders, `$1` through `$n`
+            '$' if chars.peek().is_some_and(char::is_ascii_digit) => {
+                while chars.next_if(char::is_ascii_digit).is_some() {}
+
+                stripped.push('?');
+            }
+
+            c if c.is_ascii_digit()
+                && !stripped.ends_with(|c: char| c.is_alphanumeric() || c == '_') =>
+            {
+                while chars.next_if(|c| c.is_ascii_digit() || *c == '.').is_some() {}
+
+                stripped.push('?');
+            }
+
+            // Comments, such as the tags appended by `sql_commenter`, which
+            // PostgreSQL lets nest
+            '/' if chars.next_if_eq(&'*').is_some() => {
+                let mut depth = 1;
+
+                while depth > 0 {
+                    match chars.next() {
+                        Some('*') if chars.next_if_eq(&'/').is_some() => depth -= 1,
+                        Some('/') if chars.next_if_eq(&'*').is_some() => depth += 1,
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+
+                space(&mut stripped);
+            }
+
+            '-' if chars.next_if_eq(&'-').is_some() => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+
+                space(&mut stripped);
+            }
+
+            c if c.is_whitespace() => space(&mut stripped),
+
+            c => stripped.push(c),
+        }
+    }
+
+    stripped.truncate(stripped.trim_end().len());
+    stripped
+}
+
+fn space(stripped: &mut String) {
+    if !stripped.is_empty() && !stripped.ends_with(' ') {
+        stripped.push(' ');
+    }
+}
+
+// Replaces lists such as `IN (?, ?, ?)` with `IN (...)`
+fn collapse_lists(sql: &str) -> String {
+    let mut collapsed = String::with_capacity(sql.len());
+    let mut rest = sql;
+
+    while let Some(open) = rest.find('(') {
+        let (head, tail) = rest.split_at(open + 1);
+        collapsed.push_str(head);
+        rest = tail;
+
+        let keyword = head[..open].trim_end().as_bytes();
+        let is_in = keyword.len() >= 2
+            && keyword[keyword.len() - 2..].eq_ignore_ascii_case(b"IN")
+            && (keyword.len() == 2 || keyword[keyword.len() - 3] == b' ');
+
+        let close = match tail.find(')') {
+            Some(close) if is_in => close,
+            _ => continue,
+        };
+
+        if tail[..close].split(',').all(|item| item.trim() == "?") {
+            collapsed.push_str("...");
+            rest = &tail[close..];
+        }
+    }
+
+    collapsed.push_str(rest);
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fingerprint;
+
+    #[test]
+    fn string_literals() {
+        assert_eq!(
+            fingerprint("SELECT * FROM t WHERE a = 'it''s' AND b = ''"),
+            "SELECT * FROM t WHERE a = ? AND b = ?"
+        );
+    }
+
+    #[test]
+    fn placeholders_and_numbers() {
+        assert_eq!(
+            fingerprint("SELECT * FROM t WHERE a = $1 AND b = $12 LIMIT 10 OFFSET 2.5"),
+            "SELECT * FROM t WHERE a = ? AND b = ? LIMIT ? OFFSET ?"
+        );
+        assert_eq!(
+            fingerprint("SELECT * FROM t WHERE a = 42"),
+            fingerprint("SELECT * FROM t WHERE a = $1")
+        );
+    }
+
+    #[test]
+    fn identifiers_keep_their_digits() {
+        assert_eq!(
+            fingerprint("SELECT t1.col2, v_3 FROM t1 WHERE t1.col2 = 7"),
+            "SELECT t1.col2, v_3 FROM t1 WHERE t1.col2 = ?"
+        );
+    }
+
+    #[test]
+    fn in_lists() {
+        assert_eq!(
+            fingerprint("SELECT * FROM t WHERE id IN ($1, $2, $3)"),
+            "SELECT * FROM t WHERE id IN (...)"
+        );
+        assert_eq!(
+            fingerprint("SELECT * FROM t WHERE id in (1,2)"),
+            "SELECT * FROM t WHERE id in (...)"
+        );
+
+        // Only lists of values, and only after `IN`
+        assert_eq!(
+            fingerprint("SELECT * FROM t WHERE id IN (SELECT id FROM u)"),
+            "SELECT * FROM t WHERE id IN (SELECT id FROM u)"
+        );
+        assert_eq!(fingerprint("SELECT min(1, 2)"), "SELECT min(?, ?)");
+    }
+
+    #[test]
+    fn whitespace_and_comments() {
+        let sql = "SELECT a FROM t WHERE b = ?";
+
+        assert_eq!(fingerprint("  SELECT a\n\tFROM t   WHERE b = $1 "), sql);
+        assert_eq!(
+            fingerprint("SELECT a FROM t WHERE b = $1 /*app='billing',route='%2Finvoices'*/"),
+            sql
+        );
+        assert_eq!(
+            fingerprint("/* leading /* nested */ */ SELECT a -- the a\nFROM t WHERE b = $1"),
+            sql
+        );
+    }
+}
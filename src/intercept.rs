@@ -0,0 +1,219 @@
+use crate::{
+    trace::{self, Timer},
+    AsyncError, AsyncResult,
+};
+use std::{error::Error as StdError, future::Future, panic::Location, sync::Arc, time::Duration};
+
+/// Hooks run around every operation of an [`AsyncPool`](crate::AsyncPool),
+/// registered with [`AsyncPoolBuilder::interceptor`](crate::AsyncPoolBuilder::interceptor).
+///
+/// Both hooks run on the task awaiting the operation, so they must not
+/// block.
+pub trait QueryInterceptor: Send + Sync + 'static {
+    /// Called before the operation runs. Returning an error rejects the
+    /// operation, which then fails with [`AsyncError::Rejected`] without
+    /// touching a connection.
+    fn before(&self, operation: &Operation<'_>) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        let _ = operation;
+        Ok(())
+    }
+
+    /// Called once the operation completed, including when it was rejected.
+    fn after(&self, operation: &Operation<'_>, outcome: &Outcome<'_>) {
+        let _ = (operation, outcome);
+    }
+}
+
+/// The kind of an intercepted [`Operation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OperationKind {
+    /// [`AsyncConnection::run`](crate::AsyncConnection::run)
+    Run,
+    /// [`AsyncConnection::transaction`](crate::AsyncConnection::transaction)
+    Transaction,
+    /// [`AsyncSimpleConnection::batch_execute_async`](crate::AsyncSimpleConnection::batch_execute_async)
+    BatchExecute,
+    /// [`AsyncRunQueryDsl::execute_async`](crate::AsyncRunQueryDsl::execute_async)
+    Execute,
+    /// [`AsyncRunQueryDsl::load_async`](crate::AsyncRunQueryDsl::load_async)
+    Load,
+    /// [`AsyncRunQueryDsl::get_result_async`](crate::AsyncRunQueryDsl::get_result_async)
+    GetResult,
+    /// [`AsyncRunQueryDsl::get_results_async`](crate::AsyncRunQueryDsl::get_results_async)
+    GetResults,
+    /// [`AsyncRunQueryDsl::first_async`](crate::AsyncRunQueryDsl::first_async)
+    First,
+    /// [`AsyncRunQueryDsl::load_stream`](crate::AsyncRunQueryDsl::load_stream),
+    /// which completes once the last row has been sent
+    LoadStream,
+    /// `AsyncRunQueryDsl::load_cursor_async`, which completes once the last
+    /// row has been sent
+    LoadCursor,
+}
+
+impl OperationKind {
+    // The `operation` label of its metrics
+    #[cfg(feature = "metrics")]
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            OperationKind::Run => "run",
+            OperationKind::Transaction => "transaction",
+            OperationKind::BatchExecute => "batch_execute",
+            OperationKind::Execute => "execute",
+            OperationKind::Load => "load",
+            OperationKind::GetResult => "get_result",
+            OperationKind::GetResults => "get_results",
+            OperationKind::First => "first",
+            OperationKind::LoadStream => "load_stream",
+            OperationKind::LoadCursor => "load_cursor",
+        }
+    }
+}
+
+/// An operation seen by a [`QueryInterceptor`].
+#[derive(Debug)]
+pub struct Operation<'a> {
+    kind: OperationKind,
+    pool: &'a str,
+    sql: Option<&'a str>,
+    location: Option<&'static Location<'static>>,
+}
+
+impl<'a> Operation<'a> {
+    pub fn kind(&self) -> OperationKind {
+        self.kind
+    }
+
+    /// The name of the pool, see [`AsyncPoolBuilder::name`](crate::AsyncPoolBuilder::name).
+    pub fn pool(&self) -> &str {
+        self.pool
+    }
+
+    /// The SQL of a query or batch, without the values of its bind
+    /// parameters. `None` for closures passed to `run` or `transaction`.
+    pub fn sql(&self) -> Option<&str> {
+        self.sql
+    }
+
+    /// Where a query run with [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl)
+    /// was run from.
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        self.location
+    }
+}
+
+/// How an [`Operation`] completed.
+#[derive(Debug)]
+pub struct Outcome<'a> {
+    error: Option<&'a AsyncError>,
+    elapsed: Duration,
+    pool_wait: Option<Duration>,
+    blocking: Option<Duration>,
+}
+
+impl<'a> Outcome<'a> {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn error(&self) -> Option<&AsyncError> {
+        self.error
+    }
+
+    /// The time from the call until the operation completed.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The time spent waiting for a connection, or `None` if the operation
+    /// never got one.
+    pub fn pool_wait(&self) -> Option<Duration> {
+        self.pool_wait
+    }
+
+    /// The time the connection spent running the operation, or `None` if it
+    /// never ran to completion.
+    pub fn blocking(&self) -> Option<Duration> {
+        self.blocking
+    }
+}
+
+pub(crate) type Interceptors = Arc<[Arc<dyn QueryInterceptor>]>;
+
+// An operation to run between the hooks of `interceptors`
+pub(crate) struct Interception {
+    interceptors: Interceptors,
+    kind: OperationKind,
+    pool: Arc<str>,
+    sql: Option<String>,
+    location: Option<&'static Location<'static>>,
+}
+
+impl Interception {
+    // `None` when there is nothing to intercept
+    pub(crate) fn new(
+        interceptors: &Interceptors,
+        kind: OperationKind,
+        pool: &Arc<str>,
+        sql: Option<&str>,
+        location: Option<&'static Location<'static>>,
+    ) -> Option<Self> {
+        if interceptors.is_empty() || trace::nested() {
+            return None;
+        }
+
+        Some(Interception {
+            interceptors: interceptors.clone(),
+            kind,
+            pool: pool.clone(),
+            sql: sql.map(str::to_string),
+            location,
+        })
+    }
+}
+
+// Runs `future` between the hooks of the interceptors, if any
+pub(crate) async fn run<R, F>(
+    interception: Option<&Interception>,
+    timer: &Timer,
+    future: F,
+) -> AsyncResult<R>
+where
+    F: Future<Output = AsyncResult<R>>,
+{
+    let interception = match interception {
+        Some(interception) => interception,
+        None => return future.await,
+    };
+
+    let operation = Operation {
+        kind: interception.kind,
+        pool: &interception.pool,
+        sql: interception.sql.as_deref(),
+        location: interception.location,
+    };
+
+    let rejected = interception
+        .interceptors
+        .iter()
+        .find_map(|interceptor| interceptor.before(&operation).err());
+
+    let result = match rejected {
+        Some(err) => Err(AsyncError::Rejected(err)),
+        None => future.await,
+    };
+
+    let outcome = Outcome {
+        error: result.as_ref().err(),
+        elapsed: timer.elapsed(),
+        pool_wait: timer.pool_wait(),
+        blocking: timer.blocking(),
+    };
+
+    for interceptor in interception.interceptors.iter() {
+        interceptor.after(&operation, &outcome);
+    }
+
+    result
+}
@@ -1,27 +1,274 @@
 use async_trait::async_trait;
 use diesel::{
-    connection::SimpleConnection,
+    backend::Backend,
+    connection::{SimpleConnection, TransactionManager},
     dsl::Limit,
+    query_builder::QueryFragment,
     query_dsl::{
         methods::{ExecuteDsl, LimitDsl, LoadQuery},
         RunQueryDsl,
     },
     r2d2::{ConnectionManager, Pool},
-    result::QueryResult,
+    result::{DatabaseErrorKind, QueryResult},
     Connection,
 };
-use std::{error::Error as StdError, fmt};
+use std::{
+    any::Any,
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    mem,
+    panic::{self, AssertUnwindSafe, Location},
+    pin::Pin,
+    sync::Mutex,
+    time::Duration,
+};
 use tokio::task;
 
+use self::{comment::Commented, settings::Settings};
+
+#[cfg(feature = "postgres")]
+use diesel::{
+    pg::Pg,
+    query_builder::{AsQuery, QueryId},
+    sql_types::HasSqlType,
+    Queryable,
+};
+
+pub mod budget;
+mod cancel;
+mod classify;
+mod comment;
+mod connection;
+mod context;
+#[cfg(feature = "postgres")]
+mod cursor;
+mod executor;
+mod fingerprint;
+mod intercept;
+mod manager;
+mod options;
+mod pool;
+mod retry;
+mod settings;
+mod sidecar;
+mod slow;
+mod stats;
+mod stream;
+mod timeout;
+mod trace;
+mod transaction;
+mod worker;
+
+pub use self::{
+    cancel::CancelQuery,
+    comment::SqlComment,
+    connection::AsyncPooledConnection,
+    context::{QueryContext, QueryTarget, RunQuery},
+    executor::{Auto, BlockInPlace, BlockingExecutor, Inline, Job, SpawnBlocking, ThreadPool},
+    intercept::{Operation, OperationKind, Outcome, QueryInterceptor},
+    options::{BeginTransaction, IsolationLevel, TransactionOptions},
+    pool::{AsyncPool, AsyncPoolBuilder},
+    retry::RetryPolicy,
+    slow::Explain,
+    stats::QueryStats,
+    stream::QueryStream,
+    timeout::{StatementTimeout, TimeoutDsl, WithTimeout},
+    transaction::AsyncTransaction,
+};
+
 pub type AsyncResult<R> = Result<R, AsyncError>;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum AsyncError {
     // Failed to checkout a connection
     Checkout(r2d2::Error),
 
+    // No connection became free within the pool's `connection_timeout`
+    CheckoutTimeout(Duration),
+
+    // The pool was closed with `AsyncPool::close`
+    PoolClosed,
+
     // The query failed in some way
     Error(diesel::result::Error),
+
+    // A transaction kept failing with a retryable error
+    RetriesExhausted {
+        attempts: u32,
+        error: diesel::result::Error,
+    },
+
+    // A statement ran longer than the timeout given to `with_timeout`
+    Timeout(Duration),
+
+    // The query was cancelled before it completed
+    Cancelled,
+
+    // The closure run against the connection panicked
+    Panicked(PanicPayload),
+
+    // An earlier panic or lost connection left the held connection unusable.
+    // It is discarded once the `AsyncPooledConnection` or `AsyncTransaction`
+    // is dropped.
+    Broken,
+
+    // The blocking job was dropped before it completed, e.g. because the
+    // runtime is shutting down
+    Join,
+
+    // The executor cannot be used from here, e.g. `BlockInPlace` on a
+    // `current_thread` runtime or `SpawnBlocking` outside of a runtime
+    Runtime(String),
+
+    // A `QueryInterceptor` refused to run the operation
+    Rejected(Box<dyn StdError + Send + Sync>),
+
+    // The query would go over the enforced budget of a `budget::scope`
+    OverBudget(budget::Exceeded),
+
+    // A query run with `AsyncRunQueryDsl` failed, with details of the query.
+    // Only returned with the `query-context` feature.
+    Query {
+        error: Box<AsyncError>,
+        context: Box<QueryContext>,
+    },
+}
+
+impl AsyncError {
+    /// Details of the failed query, for errors of queries run with
+    /// [`AsyncRunQueryDsl`] when the `query-context` feature is enabled.
+    pub fn context(&self) -> Option<&QueryContext> {
+        match *self {
+            AsyncError::Query { ref context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The error without the [`Query`](AsyncError::Query) details, to match
+    /// on the underlying variant.
+    pub fn without_context(&self) -> &AsyncError {
+        match *self {
+            AsyncError::Query { ref error, .. } => error,
+            _ => self,
+        }
+    }
+
+    /// The database error this error wraps, if any.
+    pub fn database_error(&self) -> Option<&diesel::result::Error> {
+        match *self.without_context() {
+            AsyncError::Error(ref err) => Some(err),
+            AsyncError::RetriesExhausted { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Whether running the query or transaction again may succeed: a
+    /// serialization failure, a deadlock, a lock timeout or, on SQLite, a
+    /// locked database.
+    ///
+    /// Diesel 1.x only reports the code of a serialization failure on
+    /// PostgreSQL. The other errors are recognised by their message, so they
+    /// are missed when the server reports its messages in a language other
+    /// than English (PostgreSQL's `lc_messages`).
+    ///
+    /// A transaction that already gave up after retrying
+    /// ([`RetriesExhausted`](AsyncError::RetriesExhausted)) is not retryable.
+    pub fn is_retryable(&self) -> bool {
+        match *self.without_context() {
+            AsyncError::Error(ref err) => classify::is_retryable(err),
+            _ => false,
+        }
+    }
+
+    /// Whether a unique constraint or primary key was violated.
+    pub fn is_unique_violation(&self) -> bool {
+        self.is_database_error(DatabaseErrorKind::UniqueViolation)
+    }
+
+    /// Whether a foreign key constraint was violated.
+    pub fn is_foreign_key_violation(&self) -> bool {
+        self.is_database_error(DatabaseErrorKind::ForeignKeyViolation)
+    }
+
+    /// The name of the constraint that was violated. Only PostgreSQL reports
+    /// it, this is always `None` on MySQL and SQLite.
+    pub fn constraint_name(&self) -> Option<&str> {
+        match self.database_error() {
+            Some(diesel::result::Error::DatabaseError(_, ref info)) => info.constraint_name(),
+            _ => None,
+        }
+    }
+
+    /// Whether the connection to the database was lost while running the
+    /// query.
+    ///
+    /// Apart from failing to send the query at all, this is recognised by
+    /// the message of the error, so it is missed when the server or client
+    /// library reports its messages in a language other than English.
+    pub fn is_connection_lost(&self) -> bool {
+        self.database_error()
+            .is_some_and(classify::is_connection_lost)
+    }
+
+    /// Whether a query expected to return a row returned none.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            *self.without_context(),
+            AsyncError::Error(diesel::result::Error::NotFound)
+        )
+    }
+
+    fn is_database_error(&self, kind: DatabaseErrorKind) -> bool {
+        match self.database_error() {
+            Some(diesel::result::Error::DatabaseError(ref actual, _)) => {
+                mem::discriminant(actual) == mem::discriminant(&kind)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The payload of a panic caught while running a closure on a connection.
+pub struct PanicPayload {
+    message: Option<String>,
+    // Only to make the error `Sync`, the payload is never shared
+    payload: Mutex<Box<dyn Any + Send>>,
+}
+
+impl PanicPayload {
+    pub(crate) fn new(payload: Box<dyn Any + Send>) -> Self {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned());
+
+        PanicPayload {
+            message,
+            payload: Mutex::new(payload),
+        }
+    }
+
+    /// The panic message, if the panic was started with a string as `panic!`
+    /// does.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// The original payload, e.g. to continue unwinding with
+    /// `std::panic::resume_unwind`.
+    pub fn into_inner(self) -> Box<dyn Any + Send> {
+        self.payload
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl fmt::Debug for PanicPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PanicPayload").field(&self.message).finish()
+    }
 }
 
 pub trait OptionalExtension<T> {
@@ -32,7 +279,7 @@ impl<T> OptionalExtension<T> for AsyncResult<T> {
     fn optional(self) -> Result<Option<T>, AsyncError> {
         match self {
             Ok(value) => Ok(Some(value)),
-            Err(AsyncError::Error(diesel::result::Error::NotFound)) => Ok(None),
+            Err(ref e) if e.is_not_found() => Ok(None),
             Err(e) => Err(e),
         }
     }
@@ -42,7 +289,39 @@ impl fmt::Display for AsyncError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             AsyncError::Checkout(ref err) => err.fmt(f),
+            AsyncError::CheckoutTimeout(timeout) => {
+                write!(f, "timed out waiting for a connection after {:?}", timeout)
+            }
+            AsyncError::PoolClosed => f.write_str("the connection pool has been closed"),
             AsyncError::Error(ref err) => err.fmt(f),
+            AsyncError::RetriesExhausted {
+                attempts,
+                ref error,
+            } => write!(f, "{} (gave up after {} attempts)", error, attempts),
+            AsyncError::Timeout(timeout) => write!(f, "statement timed out after {:?}", timeout),
+            AsyncError::Panicked(ref payload) => match payload.message() {
+                Some(message) => write!(f, "closure panicked: {}", message),
+                None => f.write_str("closure panicked"),
+            },
+            AsyncError::Broken => {
+                f.write_str("the connection was left unusable by an earlier failure")
+            }
+            AsyncError::Cancelled => f.write_str("the query was cancelled"),
+            AsyncError::Join => f.write_str("the blocking job was dropped before it completed"),
+            AsyncError::Runtime(ref message) => {
+                write!(f, "the executor cannot run here: {}", message)
+            }
+            AsyncError::Rejected(ref err) => write!(f, "rejected by an interceptor: {}", err),
+            AsyncError::OverBudget(ref exceeded) => {
+                write!(f, "over the query budget: {}", exceeded)
+            }
+            #[cfg(feature = "query-context")]
+            AsyncError::Query {
+                ref error,
+                ref context,
+            } => write!(f, "{} ({})", error, context),
+            #[cfg(not(feature = "query-context"))]
+            AsyncError::Query { ref error, .. } => error.fmt(f),
         }
     }
 }
@@ -52,10 +331,35 @@ impl StdError for AsyncError {
         match *self {
             AsyncError::Checkout(ref err) => Some(err),
             AsyncError::Error(ref err) => Some(err),
+            AsyncError::RetriesExhausted { ref error, .. } => Some(error),
+            AsyncError::Rejected(ref err) => Some(&**err),
+            AsyncError::Query { ref error, .. } => error.source(),
+            _ => None,
         }
     }
 }
 
+// Runs `f` against a connection of a plain r2d2 pool, returning a panic as an
+// error. r2d2 can't be told to drop the connection so any transaction the
+// panic left open is rolled back instead.
+fn catch_unwind<Conn, R, F>(conn: &Conn, f: F) -> AsyncResult<R>
+where
+    Conn: Connection,
+    F: FnOnce() -> AsyncResult<R>,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let manager = conn.transaction_manager();
+
+        while manager.get_transaction_depth() > 0 {
+            if manager.rollback_transaction(conn).is_err() {
+                break;
+            }
+        }
+
+        Err(AsyncError::Panicked(PanicPayload::new(payload)))
+    })
+}
+
 #[async_trait]
 pub trait AsyncSimpleConnection<Conn>
 where
@@ -72,11 +376,21 @@ where
     #[inline]
     async fn batch_execute_async(&self, query: &str) -> AsyncResult<()> {
         let self_ = self.clone();
+        let span = trace::Span::new::<Conn::Backend>("batch_execute", Some(query))
+            .pool("default", || Some(self.state()));
         let query = query.to_string();
-        task::block_in_place(move || {
-            let conn = self_.get().map_err(AsyncError::Checkout)?;
-            conn.batch_execute(&query).map_err(AsyncError::Error)
+
+        span.run(|timer| {
+            executor::run(&Auto, move || {
+                let conn = self_.get().map_err(AsyncError::Checkout)?;
+                timer.time(|| {
+                    catch_unwind(&*conn, || {
+                        conn.batch_execute(&query).map_err(AsyncError::Error)
+                    })
+                })
+            })
         })
+        .await
     }
 }
 
@@ -85,15 +399,74 @@ pub trait AsyncConnection<Conn>: AsyncSimpleConnection<Conn>
 where
     Conn: 'static + Connection,
 {
+    /// Runs `f` with a connection. `f` may borrow from the caller.
+    ///
+    /// On a plain r2d2 pool `f` runs on the calling thread: through
+    /// `block_in_place` on the multi-threaded scheduler, but inline on a
+    /// `current_thread` runtime, blocking it until `f` returns. Use
+    /// `run_owned` there, or the methods of [`AsyncRunQueryDsl`], which move
+    /// the work to `spawn_blocking`.
     async fn run<R, Func>(&self, f: Func) -> AsyncResult<R>
     where
         R: Send,
         Func: FnOnce(&Conn) -> QueryResult<R> + Send;
 
+    /// Runs `f` in a transaction. `f` may borrow from the caller, and runs
+    /// on the calling thread of a plain r2d2 pool like it does for
+    /// [`run`](AsyncConnection::run). Use `transaction_owned` on a
+    /// `current_thread` runtime.
     async fn transaction<R, Func>(&self, f: Func) -> AsyncResult<R>
     where
         R: Send,
         Func: FnOnce(&Conn) -> QueryResult<R> + Send;
+
+    // Like `run` but `f` owns everything it uses, so that it can be moved to
+    // another thread: the executor or connection thread of an `AsyncPool`,
+    // where `cancel_on_drop` can interrupt it, or `spawn_blocking` for a
+    // plain r2d2 pool on a `current_thread` runtime
+    async fn run_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.run(f).await
+    }
+
+    // Like `transaction` but `f` owns everything it uses, see `run_owned`
+    async fn transaction_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.transaction(f).await
+    }
+
+    // Like `transaction_owned` but started with backend specific options
+    // such as the isolation level
+    async fn transaction_with<R, Func>(
+        &self,
+        options: TransactionOptions,
+        f: Func,
+    ) -> AsyncResult<R>
+    where
+        Conn: BeginTransaction,
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.run_owned(move |conn| options::transaction_with(conn, &options, || f(conn)))
+            .await
+    }
+
+    // Runs `f` in a transaction, running it again in a fresh transaction when
+    // it fails with a serialization failure or deadlock
+    async fn transaction_with_retry<R, Func>(&self, policy: RetryPolicy, f: Func) -> AsyncResult<R>
+    where
+        Conn: BeginTransaction,
+        R: Send + 'static,
+        Func: Fn(&Conn) -> QueryResult<R> + Send + Sync + 'static,
+    {
+        retry::transaction_with_retry(self, policy, f).await
+    }
 }
 
 #[async_trait]
@@ -107,11 +480,15 @@ where
         R: Send,
         Func: FnOnce(&Conn) -> QueryResult<R> + Send,
     {
-        let self_ = self.clone();
-        task::block_in_place(move || {
-            let conn = self_.get().map_err(AsyncError::Checkout)?;
-            f(&*conn).map_err(AsyncError::Error)
-        })
+        trace::Span::new::<Conn::Backend>("run", None)
+            .pool("default", || Some(self.state()))
+            .run(|timer| async move {
+                executor::run_here(|| {
+                    let conn = self.get().map_err(AsyncError::Checkout)?;
+                    timer.time(|| catch_unwind(&*conn, || f(&*conn).map_err(AsyncError::Error)))
+                })
+            })
+            .await
     }
 
     #[inline]
@@ -119,89 +496,340 @@ where
     where
         R: Send,
         Func: FnOnce(&Conn) -> QueryResult<R> + Send,
+    {
+        trace::Span::new::<Conn::Backend>("transaction", None)
+            .pool("default", || Some(self.state()))
+            .run(|timer| async move {
+                executor::run_here(|| {
+                    let conn = self.get().map_err(AsyncError::Checkout)?;
+                    timer.time(|| {
+                        catch_unwind(&*conn, || {
+                            conn.transaction(|| f(&*conn)).map_err(AsyncError::Error)
+                        })
+                    })
+                })
+            })
+            .await
+    }
+
+    #[inline]
+    async fn run_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        let self_ = self.clone();
+
+        trace::Span::new::<Conn::Backend>("run", None)
+            .pool("default", || Some(self.state()))
+            .run(|timer| {
+                executor::run(&Auto, move || {
+                    let conn = self_.get().map_err(AsyncError::Checkout)?;
+                    timer.time(|| catch_unwind(&*conn, || f(&*conn).map_err(AsyncError::Error)))
+                })
+            })
+            .await
+    }
+
+    #[inline]
+    async fn transaction_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
     {
         let self_ = self.clone();
-        task::block_in_place(move || {
-            let conn = self_.get().map_err(AsyncError::Checkout)?;
-            conn.transaction(|| f(&*conn)).map_err(AsyncError::Error)
+
+        trace::Span::new::<Conn::Backend>("transaction", None)
+            .pool("default", || Some(self.state()))
+            .run(|timer| {
+                executor::run(&Auto, move || {
+                    let conn = self_.get().map_err(AsyncError::Checkout)?;
+                    timer.time(|| {
+                        catch_unwind(&*conn, || {
+                            conn.transaction(|| f(&*conn)).map_err(AsyncError::Error)
+                        })
+                    })
+                })
+            })
+            .await
+    }
+}
+
+impl<Conn> QueryTarget<Conn> for Pool<ConnectionManager<Conn>>
+where
+    Conn: 'static + Connection,
+{
+    fn settings(&self) -> Option<&Settings<Conn>> {
+        None
+    }
+
+    fn pool_state(&self) -> Option<r2d2::State> {
+        Some(self.state())
+    }
+
+    fn run_detached<'a, F>(&'a self, f: F) -> QueryFuture<'a, ()>
+    where
+        F: FnOnce(&Conn) + Send + 'static,
+    {
+        let self_ = self.clone();
+
+        Box::pin(async move {
+            let conn =
+                executor::run(&Auto, move || self_.get().map_err(AsyncError::Checkout)).await?;
+
+            task::spawn_blocking(move || {
+                let _ = catch_unwind(&*conn, || {
+                    f(&*conn);
+                    Ok(())
+                });
+            });
+
+            Ok(())
         })
     }
 }
 
-#[async_trait]
+// Queries own their binds so that they can move to `spawn_blocking` on a
+// `current_thread` runtime
+impl<Conn, T, R> RunQuery<Conn, T, R> for Pool<ConnectionManager<Conn>>
+where
+    Conn: 'static + Connection,
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    fn run_query<'a, F>(&'a self, query: T, f: F) -> QueryFuture<'a, R>
+    where
+        T: 'a,
+        R: 'a,
+        F: FnOnce(T, &Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.run_owned(move |conn| f(query, conn))
+    }
+}
+
+// Returned by the methods of `AsyncRunQueryDsl`, which can't be `async fn`s
+// as they need `#[track_caller]`
+pub(crate) type QueryFuture<'a, R> = Pin<Box<dyn Future<Output = AsyncResult<R>> + Send + 'a>>;
+
 pub trait AsyncRunQueryDsl<Conn, AsyncConn>
 where
     Conn: 'static + Connection,
 {
-    async fn execute_async(self, asc: &AsyncConn) -> AsyncResult<usize>
+    fn execute_async<'a>(self, asc: &'a AsyncConn) -> QueryFuture<'a, usize>
     where
-        Self: ExecuteDsl<Conn>;
+        Self: ExecuteDsl<Conn> + 'a,
+        AsyncConn: RunQuery<Conn, Self, usize>;
 
-    async fn load_async<U>(self, asc: &AsyncConn) -> AsyncResult<Vec<U>>
+    fn load_async<'a, U>(self, asc: &'a AsyncConn) -> QueryFuture<'a, Vec<U>>
     where
-        U: Send,
-        Self: LoadQuery<Conn, U>;
+        U: Send + 'a,
+        Self: LoadQuery<Conn, U> + 'a,
+        AsyncConn: RunQuery<Conn, Self, Vec<U>>;
 
-    async fn get_result_async<U>(self, asc: &AsyncConn) -> AsyncResult<U>
+    fn get_result_async<'a, U>(self, asc: &'a AsyncConn) -> QueryFuture<'a, U>
     where
-        U: Send,
-        Self: LoadQuery<Conn, U>;
+        U: Send + 'a,
+        Self: LoadQuery<Conn, U> + 'a,
+        AsyncConn: RunQuery<Conn, Self, U>;
 
-    async fn get_results_async<U>(self, asc: &AsyncConn) -> AsyncResult<Vec<U>>
+    fn get_results_async<'a, U>(self, asc: &'a AsyncConn) -> QueryFuture<'a, Vec<U>>
     where
-        U: Send,
-        Self: LoadQuery<Conn, U>;
+        U: Send + 'a,
+        Self: LoadQuery<Conn, U> + 'a,
+        AsyncConn: RunQuery<Conn, Self, Vec<U>>;
 
-    async fn first_async<U>(self, asc: &AsyncConn) -> AsyncResult<U>
+    fn first_async<'a, U>(self, asc: &'a AsyncConn) -> QueryFuture<'a, U>
     where
-        U: Send,
-        Self: LimitDsl,
-        Limit<Self>: LoadQuery<Conn, U>;
+        U: Send + 'a,
+        Self: LimitDsl + Sized + 'a,
+        Limit<Self>: LoadQuery<Conn, U>,
+        AsyncConn: RunQuery<Conn, Self, U>;
+
+    /// Runs the query, loads all of its rows into memory and then yields them
+    /// one at a time as a stream.
+    ///
+    /// Memory use is that of `load_async`: Diesel buffers the whole result
+    /// before the first row is sent, so this is no way to read results too
+    /// large to hold at once. Use `load_cursor_async` for those, which fetches
+    /// them in batches on PostgreSQL. The connection stays busy until every
+    /// row has been consumed or the stream is dropped.
+    fn load_stream<'a, U>(self, asc: &'a AsyncConn) -> QueryStream<'a, U>
+    where
+        U: Send + 'static,
+        Self: LoadQuery<Conn, U> + 'static;
+
+    /// Streams the rows of the query through a PostgreSQL cursor, fetching
+    /// `batch_size` rows at a time so that only one batch is held in memory.
+    ///
+    /// The cursor is read inside a transaction, or a savepoint when `asc` is
+    /// already in one, that stays open until the stream ends or is dropped.
+    #[cfg(feature = "postgres")]
+    fn load_cursor_async<'a, U>(self, asc: &'a AsyncConn, batch_size: u32) -> QueryStream<'a, U>
+    where
+        Conn: Connection<Backend = Pg>,
+        U: Send + 'static,
+        Self: AsQuery + 'static,
+        <Self as AsQuery>::Query: QueryFragment<Pg> + QueryId,
+        Pg: HasSqlType<<Self as AsQuery>::SqlType>,
+        U: Queryable<<Self as AsQuery>::SqlType, Pg>;
 }
 
-#[async_trait]
-impl<T, Conn> AsyncRunQueryDsl<Conn, Pool<ConnectionManager<Conn>>> for T
+impl<T, Conn, AsyncConn> AsyncRunQueryDsl<Conn, AsyncConn> for T
 where
-    T: Send + RunQueryDsl<Conn>,
+    T: Send + RunQueryDsl<Conn> + QueryFragment<Conn::Backend>,
     Conn: 'static + Connection,
+    <Conn::Backend as Backend>::QueryBuilder: Default,
+    AsyncConn: Sync + AsyncConnection<Conn> + QueryTarget<Conn>,
 {
-    async fn execute_async(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<usize>
+    #[track_caller]
+    fn execute_async<'a>(self, asc: &'a AsyncConn) -> QueryFuture<'a, usize>
     where
-        Self: ExecuteDsl<Conn>,
+        Self: ExecuteDsl<Conn> + 'a,
+        AsyncConn: RunQuery<Conn, Self, usize>,
     {
-        asc.run(|conn| self.execute(&*conn)).await
+        let location = Location::caller();
+        let comment = asc.settings().and_then(Settings::comment);
+
+        Box::pin(context::run(
+            asc,
+            self,
+            location,
+            OperationKind::Execute,
+            move |query, conn| match comment {
+                Some(comment) => ExecuteDsl::execute(Commented::new(query, comment), conn),
+                None => query.execute(conn),
+            },
+            |rows| trace::Rows::Affected(*rows),
+        ))
     }
 
-    async fn load_async<U>(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<Vec<U>>
+    #[track_caller]
+    fn load_async<'a, U>(self, asc: &'a AsyncConn) -> QueryFuture<'a, Vec<U>>
     where
-        U: Send,
-        Self: LoadQuery<Conn, U>,
+        U: Send + 'a,
+        Self: LoadQuery<Conn, U> + 'a,
+        AsyncConn: RunQuery<Conn, Self, Vec<U>>,
     {
-        asc.run(|conn| self.load(&*conn)).await
+        let location = Location::caller();
+        Box::pin(context::run(
+            asc,
+            self,
+            location,
+            OperationKind::Load,
+            |query, conn| query.load(conn),
+            |rows| trace::Rows::Returned(rows.len()),
+        ))
     }
 
-    async fn get_result_async<U>(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<U>
+    #[track_caller]
+    fn get_result_async<'a, U>(self, asc: &'a AsyncConn) -> QueryFuture<'a, U>
     where
-        U: Send,
-        Self: LoadQuery<Conn, U>,
+        U: Send + 'a,
+        Self: LoadQuery<Conn, U> + 'a,
+        AsyncConn: RunQuery<Conn, Self, U>,
     {
-        asc.run(|conn| self.get_result(&*conn)).await
+        let location = Location::caller();
+        Box::pin(context::run(
+            asc,
+            self,
+            location,
+            OperationKind::GetResult,
+            |query, conn| query.get_result(conn),
+            |_| trace::Rows::Returned(1),
+        ))
     }
 
-    async fn get_results_async<U>(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<Vec<U>>
+    #[track_caller]
+    fn get_results_async<'a, U>(self, asc: &'a AsyncConn) -> QueryFuture<'a, Vec<U>>
     where
-        U: Send,
-        Self: LoadQuery<Conn, U>,
+        U: Send + 'a,
+        Self: LoadQuery<Conn, U> + 'a,
+        AsyncConn: RunQuery<Conn, Self, Vec<U>>,
     {
-        asc.run(|conn| self.get_results(&*conn)).await
+        let location = Location::caller();
+        Box::pin(context::run(
+            asc,
+            self,
+            location,
+            OperationKind::GetResults,
+            |query, conn| query.get_results(conn),
+            |rows| trace::Rows::Returned(rows.len()),
+        ))
     }
 
-    async fn first_async<U>(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<U>
+    #[track_caller]
+    fn first_async<'a, U>(self, asc: &'a AsyncConn) -> QueryFuture<'a, U>
     where
-        U: Send,
-        Self: LimitDsl,
+        U: Send + 'a,
+        Self: LimitDsl + Sized + 'a,
         Limit<Self>: LoadQuery<Conn, U>,
+        AsyncConn: RunQuery<Conn, Self, U>,
+    {
+        let location = Location::caller();
+        Box::pin(context::run(
+            asc,
+            self,
+            location,
+            OperationKind::First,
+            |query, conn| query.first(conn),
+            |_| trace::Rows::Returned(1),
+        ))
+    }
+
+    #[track_caller]
+    fn load_stream<'a, U>(self, asc: &'a AsyncConn) -> QueryStream<'a, U>
+    where
+        U: Send + 'static,
+        Self: LoadQuery<Conn, U> + 'static,
     {
-        asc.run(|conn| self.first(&*conn)).await
+        let sql = context::sql(asc, &self);
+
+        stream::spawn(
+            asc,
+            sql,
+            Location::caller(),
+            OperationKind::LoadStream,
+            move |conn, sink| {
+                // Diesel has no way to read rows one at a time
+                for row in self.load(conn)? {
+                    if !sink.send(row) {
+                        break;
+                    }
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    #[cfg(feature = "postgres")]
+    #[track_caller]
+    fn load_cursor_async<'a, U>(self, asc: &'a AsyncConn, batch_size: u32) -> QueryStream<'a, U>
+    where
+        Conn: Connection<Backend = Pg>,
+        U: Send + 'static,
+        Self: AsQuery + 'static,
+        <Self as AsQuery>::Query: QueryFragment<Pg> + QueryId,
+        Pg: HasSqlType<<Self as AsQuery>::SqlType>,
+        U: Queryable<<Self as AsQuery>::SqlType, Pg>,
+    {
+        assert!(batch_size > 0, "batch_size must be at least 1");
+
+        let sql = context::sql(asc, &self);
+        let comment = asc.settings().and_then(Settings::comment);
+
+        stream::spawn(
+            asc,
+            sql,
+            Location::caller(),
+            OperationKind::LoadCursor,
+            move |conn, sink| match comment {
+                Some(comment) => {
+                    let query = Commented::new(self.as_query(), comment);
+                    cursor::load(conn, query, batch_size, sink)
+                }
+                None => cursor::load(conn, self.as_query(), batch_size, sink),
+            },
+        )
     }
 }
@@ -0,0 +1,132 @@
+use crate::{AsyncError, AsyncResult, PanicPayload};
+use diesel::{
+    r2d2::{ConnectionManager, Error, ManageConnection},
+    result::QueryResult,
+    Connection,
+};
+use std::{
+    cell::Cell,
+    ops::Deref,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+
+// Diesel's connection manager with connections that can be marked broken,
+// which r2d2 then drops instead of returning them to the pool
+pub(crate) struct Manager<Conn>
+where
+    Conn: 'static + Connection,
+{
+    inner: Arc<ConnectionManager<Conn>>,
+}
+
+impl<Conn> Manager<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) fn new(inner: Arc<ConnectionManager<Conn>>) -> Self {
+        Manager { inner }
+    }
+}
+
+impl<Conn> ManageConnection for Manager<Conn>
+where
+    Conn: 'static + Connection,
+{
+    type Connection = Managed<Conn>;
+    type Error = Error;
+
+    fn connect(&self) -> Result<Managed<Conn>, Error> {
+        let conn = self.inner.connect()?;
+
+        Ok(Managed {
+            conn,
+            broken: Cell::new(false),
+            session_id: Cell::new(None),
+        })
+    }
+
+    fn is_valid(&self, conn: &mut Managed<Conn>) -> Result<(), Error> {
+        self.inner.is_valid(&mut conn.conn)
+    }
+
+    fn has_broken(&self, conn: &mut Managed<Conn>) -> bool {
+        conn.broken.get()
+    }
+}
+
+// A connection of an `AsyncPool`, dereferencing to the Diesel connection
+pub(crate) struct Managed<Conn> {
+    conn: Conn,
+    broken: Cell<bool>,
+
+    // Cached for `CancelQuery`
+    session_id: Cell<Option<i64>>,
+}
+
+impl<Conn> Managed<Conn>
+where
+    Conn: Connection,
+{
+    // Makes r2d2 drop the connection once it is returned to the pool
+    pub(crate) fn mark_broken(&self) {
+        self.broken.set(true);
+    }
+
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken.get()
+    }
+
+    // Runs `f`, returning a panic as an error. The connection is marked
+    // broken as the panic may have left it in the middle of a transaction,
+    // and likewise when `f` failed because the connection was lost. A held
+    // connection stays with its holder until released, so `f` is not run at
+    // all once it is broken.
+    pub(crate) fn catch_unwind<R, F>(&self, f: F) -> AsyncResult<R>
+    where
+        F: FnOnce() -> AsyncResult<R>,
+    {
+        if self.is_broken() {
+            return Err(AsyncError::Broken);
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+            self.mark_broken();
+            Err(AsyncError::Panicked(PanicPayload::new(payload)))
+        });
+
+        if let Err(ref err) = result {
+            if err.is_connection_lost() {
+                self.mark_broken();
+            }
+        }
+
+        result
+    }
+
+    // Marks the connection broken unless it still answers queries
+    pub(crate) fn validate(&self) {
+        if self.conn.execute("SELECT 1").is_err() {
+            self.mark_broken();
+        }
+    }
+
+    pub(crate) fn session_id(&self, fetch: fn(&Conn) -> QueryResult<i64>) -> QueryResult<i64> {
+        if let Some(session_id) = self.session_id.get() {
+            return Ok(session_id);
+        }
+
+        let session_id = fetch(&self.conn)?;
+        self.session_id.set(Some(session_id));
+
+        Ok(session_id)
+    }
+}
+
+impl<Conn> Deref for Managed<Conn> {
+    type Target = Conn;
+
+    fn deref(&self) -> &Conn {
+        &self.conn
+    }
+}
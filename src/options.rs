@@ -0,0 +1,159 @@
+use diesel::{connection::TransactionManager, result::QueryResult, Connection};
+
+/// The isolation level of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    /// The SQL spelling of this level, e.g. `REPEATABLE READ`.
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+/// Options used to start a transaction.
+///
+/// The default options produce the backend's plain `BEGIN`. Options that a
+/// backend has no equivalent for are ignored:
+///
+/// * MySQL has no `DEFERRABLE` transactions.
+/// * SQLite transactions are always serializable. Read-only transactions use
+///   `BEGIN DEFERRED`, `Serializable` uses `BEGIN EXCLUSIVE` and any other
+///   isolation level uses `BEGIN IMMEDIATE` to take the write lock up front.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransactionOptions {
+    pub isolation: Option<IsolationLevel>,
+    pub read_only: bool,
+    pub deferrable: bool,
+}
+
+impl TransactionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = Some(isolation);
+        self
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn deferrable(mut self) -> Self {
+        self.deferrable = true;
+        self
+    }
+}
+
+/// Connections that can start a transaction with [`TransactionOptions`].
+pub trait BeginTransaction: Connection {
+    /// Starts a transaction with the given options.
+    ///
+    /// Fails with `AlreadyInTransaction` when a transaction is already open,
+    /// as options cannot be applied to a savepoint.
+    fn begin_transaction_with(&self, options: &TransactionOptions) -> QueryResult<()>;
+}
+
+#[cfg(feature = "postgres")]
+impl BeginTransaction for diesel::pg::PgConnection {
+    fn begin_transaction_with(&self, options: &TransactionOptions) -> QueryResult<()> {
+        let mut sql = String::from("BEGIN TRANSACTION");
+
+        if let Some(isolation) = options.isolation {
+            sql.push_str(" ISOLATION LEVEL ");
+            sql.push_str(isolation.as_sql());
+        }
+
+        if options.read_only {
+            sql.push_str(" READ ONLY");
+        }
+
+        if options.deferrable {
+            sql.push_str(" DEFERRABLE");
+        }
+
+        self.transaction_manager().begin_transaction_sql(self, &sql)
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl BeginTransaction for diesel::mysql::MysqlConnection {
+    fn begin_transaction_with(&self, options: &TransactionOptions) -> QueryResult<()> {
+        use diesel::{connection::SimpleConnection, result::Error};
+
+        let manager = self.transaction_manager();
+
+        if TransactionManager::<Self>::get_transaction_depth(manager) > 0 {
+            return Err(Error::AlreadyInTransaction);
+        }
+
+        // Applies to the next transaction only
+        if let Some(isolation) = options.isolation {
+            self.batch_execute(&format!(
+                "SET TRANSACTION ISOLATION LEVEL {}",
+                isolation.as_sql()
+            ))?;
+        }
+
+        let sql = if options.read_only {
+            "START TRANSACTION READ ONLY"
+        } else {
+            "START TRANSACTION"
+        };
+
+        manager.begin_transaction_sql(self, sql)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl BeginTransaction for diesel::sqlite::SqliteConnection {
+    fn begin_transaction_with(&self, options: &TransactionOptions) -> QueryResult<()> {
+        let sql = match (options.read_only, options.isolation) {
+            (true, _) => "BEGIN DEFERRED",
+            (false, None) => "BEGIN",
+            (false, Some(IsolationLevel::Serializable)) => "BEGIN EXCLUSIVE",
+            (false, Some(_)) => "BEGIN IMMEDIATE",
+        };
+
+        self.transaction_manager().begin_transaction_sql(self, sql)
+    }
+}
+
+// The same as `Connection::transaction` but started with `options`
+pub(crate) fn transaction_with<Conn, R, F>(
+    conn: &Conn,
+    options: &TransactionOptions,
+    f: F,
+) -> QueryResult<R>
+where
+    Conn: BeginTransaction,
+    F: FnOnce() -> QueryResult<R>,
+{
+    let manager = conn.transaction_manager();
+    conn.begin_transaction_with(options)?;
+
+    match f() {
+        Ok(value) => {
+            manager.commit_transaction(conn)?;
+            Ok(value)
+        }
+
+        Err(err) => {
+            manager.rollback_transaction(conn)?;
+            Err(err)
+        }
+    }
+}
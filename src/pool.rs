@@ -0,0 +1,644 @@
+use crate::{
+    cancel::{self, CancelQuery, Canceller},
+    comment::{Commenter, SqlComment},
+    connection::AsyncPooledConnection,
+    context::{QueryTarget, RunQuery},
+    executor::{self, Auto, BlockingExecutor},
+    intercept::{OperationKind, QueryInterceptor},
+    manager::{Managed, Manager},
+    options::{BeginTransaction, TransactionOptions},
+    settings::Settings,
+    slow::{Explain, ExplainFn, SlowQueryLog},
+    stats::{QueryStats, StatsTable},
+    trace::{self, Span, Timer},
+    transaction::AsyncTransaction,
+    worker::Workers,
+    AsyncConnection, AsyncError, AsyncResult, AsyncSimpleConnection, QueryFuture,
+};
+use async_trait::async_trait;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    result::QueryResult,
+    Connection,
+};
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time,
+};
+
+/// A connection pool that knows how to run blocking Diesel work.
+///
+/// Unlike a bare `r2d2::Pool`, the strategy used to run queries can be
+/// chosen per pool with [`AsyncPoolBuilder::executor`] or
+/// [`AsyncPoolBuilder::dedicated_threads`]. It applies to the queries run
+/// with [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl), which must then own
+/// their bind parameters, and to the closures passed to `run_owned` and
+/// `transaction_owned`. Closures passed to `run` and `transaction` may
+/// borrow, so they always run on the calling thread.
+pub struct AsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pool: Pool<Manager<Conn>>,
+    mode: Mode<Conn>,
+    // One permit per connection; waiting for a permit is how tasks queue for
+    // a connection without blocking a runtime thread
+    permits: Arc<Semaphore>,
+    canceller: Option<Arc<Canceller<Conn>>>,
+    settings: Arc<Settings<Conn>>,
+}
+
+enum Mode<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Check out a connection and run on the given executor
+    Executor(Arc<dyn BlockingExecutor>),
+
+    // Run on the worker thread that owns the connection
+    Dedicated(Arc<Workers<Conn>>),
+}
+
+impl<Conn> Clone for Mode<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn clone(&self) -> Self {
+        match *self {
+            Mode::Executor(ref executor) => Mode::Executor(executor.clone()),
+            Mode::Dedicated(ref workers) => Mode::Dedicated(workers.clone()),
+        }
+    }
+}
+
+impl<Conn> AsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    /// Creates a new pool with the default configuration.
+    pub fn new(manager: ConnectionManager<Conn>) -> Result<Self, r2d2::Error> {
+        Self::builder().build(manager)
+    }
+
+    pub fn builder() -> AsyncPoolBuilder<Conn> {
+        AsyncPoolBuilder {
+            inner: Pool::builder(),
+            executor: Some(Arc::new(Auto)),
+            canceller: None,
+            name: "default".into(),
+            slow_query_threshold: None,
+            explain: None,
+            interceptors: Vec::new(),
+            commenter: None,
+            query_stats: false,
+        }
+    }
+
+    pub fn state(&self) -> r2d2::State {
+        self.pool.state()
+    }
+
+    pub fn max_size(&self) -> u32 {
+        self.pool.max_size()
+    }
+
+    /// The name the pool was given with [`AsyncPoolBuilder::name`].
+    pub fn name(&self) -> &str {
+        &self.settings.name
+    }
+
+    /// Statistics of the queries run through the pool, one per fingerprint,
+    /// the queries taking the most time in total first. Empty unless the
+    /// pool was built with [`AsyncPoolBuilder::track_query_stats`].
+    ///
+    /// Statements that differ only in their literals, bind parameters or the
+    /// length of their `IN` lists share a fingerprint, as with
+    /// `pg_stat_statements`.
+    pub fn query_stats(&self) -> Vec<QueryStats> {
+        match self.settings.query_stats {
+            Some(ref query_stats) => query_stats.snapshot(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The executor used to run blocking work, or `None` when the pool uses
+    /// dedicated connection threads.
+    pub fn executor(&self) -> Option<&dyn BlockingExecutor> {
+        match self.mode {
+            Mode::Executor(ref executor) => Some(&**executor),
+            Mode::Dedicated(_) => None,
+        }
+    }
+
+    /// Closes the pool. Queries that are running or hold a connection carry
+    /// on, but any further checkout fails with [`AsyncError::PoolClosed`].
+    pub fn close(&self) {
+        self.permits.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.permits.is_closed()
+    }
+
+    // Waits, without blocking, until a connection is free. The wait shares
+    // the pool's `connection_timeout`. Dropping the returned future gives up
+    // its place in the queue.
+    async fn acquire(&self) -> AsyncResult<OwnedSemaphorePermit> {
+        let timeout = self.pool.connection_timeout();
+
+        match time::timeout(timeout, self.permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) => Err(AsyncError::PoolClosed),
+            Err(_) => Err(AsyncError::CheckoutTimeout(timeout)),
+        }
+    }
+
+    /// Checks out a connection that stays with the caller until dropped, so
+    /// that a sequence of queries runs on the same physical connection.
+    pub async fn get_async(&self) -> AsyncResult<AsyncPooledConnection<Conn>> {
+        let timer = Timer::new();
+        let permit = self.acquire().await?;
+
+        let conn = match self.mode {
+            Mode::Executor(ref executor) => {
+                let pool = self.pool.clone();
+                let conn = executor::run(&**executor, move || {
+                    pool.get().map_err(AsyncError::Checkout)
+                })
+                .await?;
+
+                AsyncPooledConnection::new(
+                    executor.clone(),
+                    conn,
+                    permit,
+                    self.canceller.clone(),
+                    self.settings.clone(),
+                )
+            }
+
+            Mode::Dedicated(ref workers) => {
+                let lease = workers.lease(permit);
+
+                // Make sure the worker holds a connection before handing it out
+                lease.run(|_| Ok(())).await?;
+
+                AsyncPooledConnection::leased(lease, self.settings.clone())
+            }
+        };
+
+        trace::record_checkout(&self.settings.name, timer.elapsed());
+
+        Ok(conn)
+    }
+
+    /// Checks out a connection and starts a transaction on it.
+    pub async fn begin(&self) -> AsyncResult<AsyncTransaction<Conn>> {
+        self.get_async().await?.begin().await
+    }
+
+    /// Checks out a connection and starts a transaction on it with the given
+    /// options.
+    pub async fn begin_with(
+        &self,
+        options: TransactionOptions,
+    ) -> AsyncResult<AsyncTransaction<Conn>>
+    where
+        Conn: BeginTransaction,
+    {
+        self.get_async().await?.begin_with(options).await
+    }
+
+    // Instruments an operation of `AsyncConnection`
+    fn span(&self, operation: &'static str, kind: OperationKind, sql: Option<&str>) -> Span {
+        Span::new::<Conn::Backend>(operation, sql)
+            .pool(&self.settings.name, || Some(self.pool.state()))
+            .intercept(self.settings.intercept(kind, sql, None))
+    }
+
+    // Runs `f` against a checked out connection using the configured mode
+    async fn with_conn<R, F>(&self, f: F) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Managed<Conn>) -> AsyncResult<R> + Send + 'static,
+    {
+        let permit = self.acquire().await?;
+
+        match self.mode {
+            Mode::Executor(ref executor) => {
+                let pool = self.pool.clone();
+                let guard = cancel::Guard::new(self.canceller.as_ref());
+                let flight = guard.flight();
+
+                executor::run(&**executor, move || {
+                    // Released only once the connection is back in the pool
+                    let _permit = permit;
+
+                    let conn = pool.get().map_err(AsyncError::Checkout)?;
+                    conn.catch_unwind(|| cancel::run(flight, &conn, f))
+                })
+                .await
+            }
+
+            Mode::Dedicated(ref workers) => workers.lease(permit).run(f).await,
+        }
+    }
+
+    // Runs `f` against a checked out connection on the calling thread, so
+    // that it may borrow
+    async fn with_conn_here<R, F>(&self, f: F) -> AsyncResult<R>
+    where
+        F: FnOnce(&Conn) -> AsyncResult<R>,
+    {
+        let permit = self.acquire().await?;
+
+        match self.mode {
+            Mode::Executor(_) => executor::run_here(|| {
+                // Released only once the connection is back in the pool
+                let _permit = permit;
+
+                let conn = self.pool.get().map_err(AsyncError::Checkout)?;
+                conn.catch_unwind(|| f(&conn))
+            }),
+
+            Mode::Dedicated(ref workers) => workers.lease(permit).run_here(f),
+        }
+    }
+}
+
+impl<Conn> Clone for AsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn clone(&self) -> Self {
+        AsyncPool {
+            pool: self.pool.clone(),
+            mode: self.mode.clone(),
+            permits: self.permits.clone(),
+            canceller: self.canceller.clone(),
+            settings: self.settings.clone(),
+        }
+    }
+}
+
+impl<Conn> fmt::Debug for AsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncPool")
+            .field("name", &self.settings.name)
+            .field("state", &self.pool.state())
+            .field("executor", &self.executor())
+            .finish()
+    }
+}
+
+/// A builder for [`AsyncPool`].
+///
+/// Pool settings mirror those of `r2d2::Builder`.
+pub struct AsyncPoolBuilder<Conn>
+where
+    Conn: 'static + Connection,
+{
+    inner: r2d2::Builder<Manager<Conn>>,
+    // `None` selects dedicated connection threads
+    executor: Option<Arc<dyn BlockingExecutor>>,
+    canceller: Option<NewCanceller<Conn>>,
+    name: Arc<str>,
+    slow_query_threshold: Option<Duration>,
+    explain: Option<ExplainFn<Conn>>,
+    interceptors: Vec<Arc<dyn QueryInterceptor>>,
+    commenter: Option<Commenter>,
+    query_stats: bool,
+}
+
+type NewCanceller<Conn> = fn(Arc<ConnectionManager<Conn>>) -> Canceller<Conn>;
+
+impl<Conn> AsyncPoolBuilder<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub fn max_size(mut self, max_size: u32) -> Self {
+        self.inner = self.inner.max_size(max_size);
+        self
+    }
+
+    pub fn min_idle(mut self, min_idle: Option<u32>) -> Self {
+        self.inner = self.inner.min_idle(min_idle);
+        self
+    }
+
+    pub fn test_on_check_out(mut self, test_on_check_out: bool) -> Self {
+        self.inner = self.inner.test_on_check_out(test_on_check_out);
+        self
+    }
+
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> Self {
+        self.inner = self.inner.max_lifetime(max_lifetime);
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.inner = self.inner.idle_timeout(idle_timeout);
+        self
+    }
+
+    pub fn connection_timeout(mut self, connection_timeout: Duration) -> Self {
+        self.inner = self.inner.connection_timeout(connection_timeout);
+        self
+    }
+
+    /// Sets the strategy used to run blocking work. Defaults to [`Auto`].
+    pub fn executor<E>(mut self, executor: E) -> Self
+    where
+        E: BlockingExecutor + 'static,
+    {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Pins every connection to its own OS thread instead of using an
+    /// executor. One thread is started per `max_size`. A thread keeps the
+    /// connection it checks out for a query, or for as long as an
+    /// [`AsyncPooledConnection`] is held, and then returns it to the pool.
+    /// The runtime never blocks and the number of blocked threads is bounded
+    /// by the pool size.
+    pub fn dedicated_threads(mut self) -> Self {
+        self.executor = None;
+        self
+    }
+
+    /// Cancels the statement a query is running when the future waiting on
+    /// it is dropped, for example by `tokio::time::timeout`. Cancel requests
+    /// are sent one at a time from a thread with a connection of its own,
+    /// and the cancelled connection is checked before it is used again. A
+    /// statement is left to finish if too many requests are already waiting.
+    ///
+    /// This costs one extra round trip per connection, to look up the session
+    /// to cancel. Queries can only be interrupted when they do not run on the
+    /// task awaiting them, so use this with [`SpawnBlocking`](crate::SpawnBlocking),
+    /// a [`ThreadPool`](crate::ThreadPool) or dedicated threads rather than
+    /// [`Auto`] or [`Inline`](crate::Inline).
+    pub fn cancel_on_drop(mut self) -> Self
+    where
+        Conn: CancelQuery,
+    {
+        self.canceller = Some(Canceller::new);
+        self
+    }
+
+    /// Names the pool, to tell pools apart in metrics. Defaults to
+    /// `"default"`.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Logs every query run with [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl)
+    /// that takes longer than `threshold`, including the time spent waiting
+    /// for a connection. The SQL, duration and caller are logged as a
+    /// warning with the `log` crate, under the target
+    /// `tokio_diesel::slow_query`.
+    pub fn slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    /// Adds the plan of the query to the slow query log. Queries are
+    /// explained one at a time on a thread with a connection of its own, so
+    /// the log record is only written once the plan is known. The plan of
+    /// statements that differ only in their values is logged at most every
+    /// ten minutes, and slow queries are logged without a plan while too
+    /// many wait to be explained. Has no effect without a
+    /// [`slow_query_threshold`](AsyncPoolBuilder::slow_query_threshold).
+    pub fn explain_slow_queries(mut self) -> Self
+    where
+        Conn: Explain,
+    {
+        self.explain = Some(Conn::explain);
+        self
+    }
+
+    /// Adds an interceptor whose hooks run around every operation of the
+    /// pool and of the connections and transactions taken from it.
+    /// Interceptors run in the order they were added.
+    pub fn interceptor<I>(mut self, interceptor: I) -> Self
+    where
+        I: QueryInterceptor,
+    {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Appends a [`SqlComment`] to every statement run with
+    /// [`execute_async`](crate::AsyncRunQueryDsl::execute_async) (with or
+    /// without a timeout) and to the query of `load_cursor_async` on PostgreSQL,
+    /// holding the tags of the enclosing [`SqlComment::scope`] as amended by
+    /// `commenter`. `commenter` runs on the task issuing the statement, so it
+    /// can add the `traceparent` of the current tracing span or set tags such
+    /// as `app` for the whole pool. Statements without tags are left as they
+    /// are.
+    ///
+    /// Other queries that return rows run uncommented: they may be any
+    /// `LoadQuery`, such as a `sql_query` loaded by name, and Diesel 1.x
+    /// offers no way to change their SQL without knowing how their rows are
+    /// read. Commented statements are not kept in Diesel's prepared
+    /// statement cache, as their comments rarely repeat.
+    pub fn sql_commenter<F>(mut self, commenter: F) -> Self
+    where
+        F: Fn(&mut SqlComment) + Send + Sync + 'static,
+    {
+        self.commenter = Some(Arc::new(commenter));
+        self
+    }
+
+    /// Keeps statistics of the queries run with
+    /// [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl), grouped by fingerprint,
+    /// for [`AsyncPool::query_stats`].
+    pub fn track_query_stats(mut self) -> Self {
+        self.query_stats = true;
+        self
+    }
+
+    fn settings(&self, manager: &Arc<ConnectionManager<Conn>>) -> Arc<Settings<Conn>> {
+        let slow_query_log = self.slow_query_threshold.map(|threshold| {
+            let explain = self.explain.map(|explain| (manager.clone(), explain));
+            SlowQueryLog::new(threshold, explain)
+        });
+
+        Arc::new(Settings {
+            name: self.name.clone(),
+            slow_query_log,
+            interceptors: self.interceptors.clone().into(),
+            commenter: self.commenter.clone(),
+            query_stats: if self.query_stats {
+                Some(StatsTable::default())
+            } else {
+                None
+            },
+        })
+    }
+
+    fn finish(
+        executor: Option<Arc<dyn BlockingExecutor>>,
+        canceller: Option<Arc<Canceller<Conn>>>,
+        settings: Arc<Settings<Conn>>,
+        pool: Pool<Manager<Conn>>,
+    ) -> AsyncPool<Conn> {
+        let mode = match executor {
+            Some(executor) => Mode::Executor(executor),
+            None => Mode::Dedicated(Arc::new(Workers::new(&pool, canceller.clone()))),
+        };
+
+        let permits = Arc::new(Semaphore::new(pool.max_size() as usize));
+
+        AsyncPool {
+            pool,
+            mode,
+            permits,
+            canceller,
+            settings,
+        }
+    }
+
+    /// Consumes the builder, returning a new pool once `min_idle`
+    /// connections have been established.
+    pub fn build(self, manager: ConnectionManager<Conn>) -> Result<AsyncPool<Conn>, r2d2::Error> {
+        let manager = Arc::new(manager);
+        let canceller = self.canceller.map(|new| Arc::new(new(manager.clone())));
+        let settings = self.settings(&manager);
+        let pool = self.inner.build(Manager::new(manager))?;
+
+        Ok(Self::finish(self.executor, canceller, settings, pool))
+    }
+
+    /// Consumes the builder, returning a new pool without waiting for any
+    /// connections to be established.
+    pub fn build_unchecked(self, manager: ConnectionManager<Conn>) -> AsyncPool<Conn> {
+        let manager = Arc::new(manager);
+        let canceller = self.canceller.map(|new| Arc::new(new(manager.clone())));
+        let settings = self.settings(&manager);
+        let pool = self.inner.build_unchecked(Manager::new(manager));
+
+        Self::finish(self.executor, canceller, settings, pool)
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for AsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> AsyncResult<()> {
+        let span = self.span("batch_execute", OperationKind::BatchExecute, Some(query));
+        let query = query.to_string();
+
+        span.run(|timer| {
+            self.with_conn(move |conn| {
+                timer.time(|| conn.batch_execute(&query).map_err(AsyncError::Error))
+            })
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for AsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send,
+    {
+        self.span("run", OperationKind::Run, None)
+            .run(|timer| {
+                self.with_conn_here(move |conn| timer.time(|| f(conn).map_err(AsyncError::Error)))
+            })
+            .await
+    }
+
+    #[inline]
+    async fn transaction<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send,
+    {
+        self.span("transaction", OperationKind::Transaction, None)
+            .run(|timer| {
+                self.with_conn_here(move |conn| {
+                    timer.time(|| conn.transaction(|| f(conn)).map_err(AsyncError::Error))
+                })
+            })
+            .await
+    }
+
+    #[inline]
+    async fn run_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.span("run", OperationKind::Run, None)
+            .run(|timer| {
+                self.with_conn(move |conn| timer.time(|| f(conn).map_err(AsyncError::Error)))
+            })
+            .await
+    }
+
+    #[inline]
+    async fn transaction_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.span("transaction", OperationKind::Transaction, None)
+            .run(|timer| {
+                self.with_conn(move |conn| {
+                    timer.time(|| conn.transaction(|| f(conn)).map_err(AsyncError::Error))
+                })
+            })
+            .await
+    }
+}
+
+impl<Conn> QueryTarget<Conn> for AsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn settings(&self) -> Option<&Settings<Conn>> {
+        Some(&self.settings)
+    }
+
+    fn pool_state(&self) -> Option<r2d2::State> {
+        Some(self.pool.state())
+    }
+
+    // The job keeps the connection checked out until it finishes
+    fn run_detached<'a, F>(&'a self, f: F) -> QueryFuture<'a, ()>
+    where
+        F: FnOnce(&Conn) + Send + 'static,
+    {
+        Box::pin(async move { self.get_async().await?.run_detached(f).await })
+    }
+}
+
+impl<Conn, T, R> RunQuery<Conn, T, R> for AsyncPool<Conn>
+where
+    Conn: 'static + Connection,
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    fn run_query<'a, F>(&'a self, query: T, f: F) -> QueryFuture<'a, R>
+    where
+        T: 'a,
+        R: 'a,
+        F: FnOnce(T, &Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.run_owned(move |conn| f(query, conn))
+    }
+}
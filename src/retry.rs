@@ -0,0 +1,141 @@
+use crate::{
+    classify::is_retryable,
+    options::{BeginTransaction, TransactionOptions},
+    AsyncConnection, AsyncError, AsyncResult,
+};
+use diesel::result::QueryResult;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time;
+
+/// Controls how [`transaction_with_retry`](crate::AsyncConnection::transaction_with_retry)
+/// retries transactions that failed with a serialization failure or a
+/// deadlock.
+///
+/// Each retry waits for an exponentially growing delay, starting at
+/// `initial_backoff` and capped at `max_backoff`. With jitter enabled (the
+/// default) the actual delay is picked at random from the upper half of that
+/// range so that conflicting transactions do not retry in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: u32,
+    jitter: bool,
+    options: TransactionOptions,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2,
+            jitter: true,
+            options: TransactionOptions::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The total number of times the transaction is run, including the first.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        assert!(max_attempts > 0, "max_attempts must be at least 1");
+
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Options used to start every attempt, e.g. `SERIALIZABLE` isolation.
+    pub fn transaction_options(mut self, options: TransactionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    // The delay before running attempt `attempt + 1`
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt - 1);
+        let delay = self
+            .initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff));
+
+        if self.jitter {
+            let random = RandomState::new().build_hasher().finish();
+            let half = delay / 2;
+            let nanos = half.as_nanos() as u64;
+
+            half + Duration::from_nanos(if nanos == 0 { 0 } else { random % nanos })
+        } else {
+            delay
+        }
+    }
+}
+
+pub(crate) async fn transaction_with_retry<Conn, AsyncConn, R, F>(
+    asc: &AsyncConn,
+    policy: RetryPolicy,
+    f: F,
+) -> AsyncResult<R>
+where
+    Conn: 'static + BeginTransaction,
+    AsyncConn: AsyncConnection<Conn> + Sync + ?Sized,
+    R: Send + 'static,
+    F: Fn(&Conn) -> QueryResult<R> + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let mut attempt = 1;
+
+    loop {
+        let f = f.clone();
+        let result = asc
+            .transaction_with(policy.options, move |conn: &Conn| f(conn))
+            .await;
+
+        match result {
+            Err(AsyncError::Error(err)) if is_retryable(&err) => {
+                if attempt >= policy.max_attempts {
+                    return Err(AsyncError::RetriesExhausted {
+                        attempts: attempt,
+                        error: err,
+                    });
+                }
+
+                time::sleep(policy.backoff(attempt)).await;
+                attempt += 1;
+            }
+
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{
+    comment::{self, Commenter},
+    intercept::{Interception, Interceptors, OperationKind},
+    slow::SlowQueryLog,
+    stats::StatsTable,
+};
+use diesel::Connection;
+use std::{panic::Location, sync::Arc};
+
+/// Settings of an [`AsyncPool`](crate::AsyncPool), shared with its
+/// connections and transactions so that they apply to every query run
+/// through them.
+pub struct Settings<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Labels the pool's metrics
+    pub(crate) name: Arc<str>,
+
+    pub(crate) slow_query_log: Option<SlowQueryLog<Conn>>,
+
+    pub(crate) interceptors: Interceptors,
+
+    pub(crate) commenter: Option<Commenter>,
+
+    pub(crate) query_stats: Option<StatsTable>,
+}
+
+impl<Conn> Settings<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // The operation to hand to the pool's interceptors, if it has any
+    pub(crate) fn intercept(
+        &self,
+        kind: OperationKind,
+        sql: Option<&str>,
+        location: Option<&'static Location<'static>>,
+    ) -> Option<Interception> {
+        Interception::new(&self.interceptors, kind, &self.name, sql, location)
+    }
+
+    // The comment to append to a statement issued on the current task
+    pub(crate) fn comment(&self) -> Option<String> {
+        self.commenter.as_ref().and_then(comment::render)
+    }
+}
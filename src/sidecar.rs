@@ -0,0 +1,68 @@
+use diesel::{
+    r2d2::{ConnectionManager, Error, ManageConnection},
+    result::QueryResult,
+    Connection,
+};
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+};
+
+type Job<Conn> = Box<dyn FnOnce(Result<&Conn, Error>) -> QueryResult<()> + Send>;
+
+// A thread with a connection of its own, outside of the pool as the pool may
+// have none to spare, for the blocking work done on behalf of a pool: sending
+// cancel requests and explaining slow queries. Jobs run one at a time and at
+// most `capacity` wait in the queue, so a burst of them costs one thread and
+// one connection rather than one of each per job.
+pub(crate) struct Sidecar<Conn>
+where
+    Conn: 'static + Connection,
+{
+    sender: mpsc::SyncSender<Job<Conn>>,
+}
+
+impl<Conn> Sidecar<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) fn new(name: &str, manager: Arc<ConnectionManager<Conn>>, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job<Conn>>(capacity);
+
+        thread::Builder::new()
+            .name(name.into())
+            .spawn(move || {
+                let mut conn = None;
+
+                // Exits once the owner (and so the sender) is dropped
+                for job in receiver {
+                    if conn.is_none() {
+                        match manager.connect() {
+                            Ok(new) => conn = Some(new),
+                            Err(err) => {
+                                let _ = job(Err(err));
+                                continue;
+                            }
+                        }
+                    }
+
+                    // The connection may be gone, open a new one next time
+                    if job(Ok(conn.as_ref().unwrap())).is_err() {
+                        conn = None;
+                    }
+                }
+            })
+            .expect("failed to spawn sidecar thread");
+
+        Sidecar { sender }
+    }
+
+    // Queues `job`, which is given the connection or the error opening it.
+    // Returns `false` without running it when the queue is full.
+    pub(crate) fn submit<F>(&self, job: F) -> bool
+    where
+        F: FnOnce(Result<&Conn, Error>) -> QueryResult<()> + Send + 'static,
+    {
+        self.sender.try_send(Box::new(job)).is_ok()
+    }
+}
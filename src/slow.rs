@@ -0,0 +1,271 @@
+use crate::{fingerprint::fingerprint, sidecar::Sidecar};
+use diesel::{r2d2::ConnectionManager, result::QueryResult, Connection};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    panic::Location,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Slow queries waiting to be explained before further ones are logged
+// without their plan
+const QUEUE: usize = 16;
+
+// How often the plan of statements with the same fingerprint is logged
+const EXPLAIN_EVERY: Duration = Duration::from_secs(600);
+
+// Fingerprints remembered at most, further statements are not explained
+const FINGERPRINTS: usize = 1024;
+
+/// Connections that can show the plan of a query. Used by
+/// [`AsyncPoolBuilder::explain_slow_queries`](crate::AsyncPoolBuilder::explain_slow_queries).
+///
+/// The SQL is explained without the values of its bind parameters:
+///
+/// * PostgreSQL shows the generic plan with `EXPLAIN (FORMAT JSON)`.
+/// * MySQL uses `EXPLAIN FORMAT=JSON` with `NULL` for every parameter.
+/// * SQLite uses `EXPLAIN QUERY PLAN`, leaving the parameters unbound.
+pub trait Explain: Connection {
+    /// Returns the plan of `sql`, as rendered by Diesel with placeholders for
+    /// its bind parameters. This is called on a new connection, never on the
+    /// one that ran the query.
+    fn explain(&self, sql: &str) -> QueryResult<String>;
+}
+
+// A single column of the output of `EXPLAIN`
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "sqlite"))]
+struct Plan(String);
+
+#[cfg(feature = "postgres")]
+impl diesel::deserialize::QueryableByName<diesel::pg::Pg> for Plan {
+    fn build<R>(row: &R) -> diesel::deserialize::Result<Self>
+    where
+        R: diesel::row::NamedRow<diesel::pg::Pg>,
+    {
+        // Quoted as libpq lowercases column names otherwise
+        row.get::<diesel::sql_types::Text, String>("\"QUERY PLAN\"")
+            .map(Plan)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl Explain for diesel::pg::PgConnection {
+    fn explain(&self, sql: &str) -> QueryResult<String> {
+        use diesel::{connection::SimpleConnection, sql_query, RunQueryDsl};
+
+        // `EXPLAIN` can't take parameters before PostgreSQL 16, so prepare
+        // the statement and explain executing it. A generic plan doesn't
+        // depend on the values passed.
+        self.batch_execute(&format!(
+            "SET plan_cache_mode = force_generic_plan; PREPARE tokio_diesel_explain AS {}",
+            sql
+        ))?;
+
+        let params = match placeholders(sql) {
+            0 => String::new(),
+            n => format!("({})", vec!["NULL"; n].join(", ")),
+        };
+
+        let plan = sql_query(format!(
+            "EXPLAIN (FORMAT JSON) EXECUTE tokio_diesel_explain{}",
+            params
+        ))
+        .get_result::<Plan>(self)?;
+
+        self.batch_execute("DEALLOCATE tokio_diesel_explain; RESET plan_cache_mode")?;
+
+        Ok(plan.0)
+    }
+}
+
+// The number of parameters of a PostgreSQL statement, `$1` through `$n`
+#[cfg(feature = "postgres")]
+fn placeholders(sql: &str) -> usize {
+    sql.split('$')
+        .skip(1)
+        .filter_map(|rest| {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            rest[..digits].parse::<usize>().ok()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(feature = "mysql")]
+impl diesel::deserialize::QueryableByName<diesel::mysql::Mysql> for Plan {
+    fn build<R>(row: &R) -> diesel::deserialize::Result<Self>
+    where
+        R: diesel::row::NamedRow<diesel::mysql::Mysql>,
+    {
+        row.get::<diesel::sql_types::Text, String>("EXPLAIN")
+            .map(Plan)
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl Explain for diesel::mysql::MysqlConnection {
+    fn explain(&self, sql: &str) -> QueryResult<String> {
+        use diesel::{sql_query, RunQueryDsl};
+
+        sql_query(format!("EXPLAIN FORMAT=JSON {}", sql.replace('?', "NULL")))
+            .get_result::<Plan>(self)
+            .map(|plan| plan.0)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl diesel::deserialize::QueryableByName<diesel::sqlite::Sqlite> for Plan {
+    fn build<R>(row: &R) -> diesel::deserialize::Result<Self>
+    where
+        R: diesel::row::NamedRow<diesel::sqlite::Sqlite>,
+    {
+        row.get::<diesel::sql_types::Text, String>("detail")
+            .map(Plan)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Explain for diesel::sqlite::SqliteConnection {
+    fn explain(&self, sql: &str) -> QueryResult<String> {
+        use diesel::{sql_query, RunQueryDsl};
+
+        let steps = sql_query(format!("EXPLAIN QUERY PLAN {}", sql)).load::<Plan>(self)?;
+        let steps: Vec<_> = steps.into_iter().map(|step| step.0).collect();
+
+        Ok(steps.join("\n"))
+    }
+}
+
+pub(crate) type ExplainFn<Conn> = fn(&Conn, &str) -> QueryResult<String>;
+
+// Logs queries run with `AsyncRunQueryDsl` that take longer than `threshold`
+pub(crate) struct SlowQueryLog<Conn>
+where
+    Conn: 'static + Connection,
+{
+    threshold: Duration,
+    explainer: Option<Explainer<Conn>>,
+}
+
+struct Explainer<Conn>
+where
+    Conn: 'static + Connection,
+{
+    sidecar: Sidecar<Conn>,
+    explain: ExplainFn<Conn>,
+
+    // When the plan of each fingerprint was last looked up
+    explained: Mutex<HashMap<String, Instant>>,
+}
+
+impl<Conn> Explainer<Conn>
+where
+    Conn: 'static + Connection,
+{
+    // Whether to look up the plan of `sql`: not if the plan of a statement
+    // with the same fingerprint was looked up within `EXPLAIN_EVERY`
+    fn claim(&self, sql: &str) -> bool {
+        let now = Instant::now();
+        let mut explained = self.explained.lock().unwrap();
+
+        if explained.len() >= FINGERPRINTS {
+            explained.retain(|_, at| now - *at < EXPLAIN_EVERY);
+        }
+
+        let full = explained.len() >= FINGERPRINTS;
+
+        match explained.entry(fingerprint(sql)) {
+            Entry::Occupied(mut entry) if now - *entry.get() >= EXPLAIN_EVERY => {
+                entry.insert(now);
+                true
+            }
+
+            Entry::Vacant(entry) if !full => {
+                entry.insert(now);
+                true
+            }
+
+            _ => false,
+        }
+    }
+}
+
+impl<Conn> SlowQueryLog<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) fn new(
+        threshold: Duration,
+        explain: Option<(Arc<ConnectionManager<Conn>>, ExplainFn<Conn>)>,
+    ) -> Self {
+        let explainer = explain.map(|(manager, explain)| Explainer {
+            sidecar: Sidecar::new("tokio-diesel-explain", manager, QUEUE),
+            explain,
+            explained: Mutex::default(),
+        });
+
+        SlowQueryLog {
+            threshold,
+            explainer,
+        }
+    }
+
+    pub(crate) fn record(
+        &self,
+        sql: Option<&str>,
+        elapsed: Duration,
+        location: &'static Location<'static>,
+    ) {
+        if elapsed < self.threshold {
+            return;
+        }
+
+        let sql = sql.unwrap_or("<unknown>");
+
+        let explainer = match self.explainer {
+            Some(ref explainer) if explainer.claim(sql) => explainer,
+            _ => return warn(elapsed, location, sql, None),
+        };
+
+        // Explaining blocks and this may be a runtime thread
+        let explain = explainer.explain;
+        let statement = sql.to_string();
+        let queued = explainer.sidecar.submit(move |conn| {
+            let (plan, result) = match conn.map(|conn| explain(conn, &statement)) {
+                Ok(Ok(plan)) => (plan, Ok(())),
+                Ok(Err(err)) => (format!("<failed to explain: {}>", err), Err(err)),
+                Err(err) => (format!("<failed to explain: {}>", err), Ok(())),
+            };
+
+            warn(elapsed, location, &statement, Some(&plan));
+            result
+        });
+
+        if !queued {
+            warn(elapsed, location, sql, None);
+        }
+    }
+}
+
+fn warn(elapsed: Duration, location: &'static Location<'static>, sql: &str, plan: Option<&str>) {
+    match plan {
+        Some(plan) => log::warn!(
+            target: "tokio_diesel::slow_query",
+            "slow query took {:?} at {}: {}\nplan: {}",
+            elapsed,
+            location,
+            sql,
+            plan
+        ),
+
+        None => log::warn!(
+            target: "tokio_diesel::slow_query",
+            "slow query took {:?} at {}: {}",
+            elapsed,
+            location,
+            sql
+        ),
+    }
+}
@@ -0,0 +1,181 @@
+use crate::fingerprint::fingerprint;
+use std::{cmp::Reverse, collections::HashMap, sync::Mutex, time::Duration};
+
+/// Statistics of the queries sharing a fingerprint, from
+/// [`AsyncPool::query_stats`](crate::AsyncPool::query_stats).
+///
+/// Times are those the connection spent running the query, leaving out the
+/// wait for a connection.
+#[derive(Debug, Clone)]
+pub struct QueryStats {
+    fingerprint: String,
+    calls: u64,
+    errors: u64,
+    total_time: Duration,
+    mean_time: Duration,
+    p99_time: Duration,
+}
+
+impl QueryStats {
+    /// The SQL of the queries with literals and bind parameters replaced by
+    /// `?` and `IN` lists collapsed to `IN (...)`.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.calls
+    }
+
+    /// The calls that failed, including those that never got a connection.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.total_time
+    }
+
+    pub fn mean_time(&self) -> Duration {
+        self.mean_time
+    }
+
+    /// The time 99% of the calls took at most, overstated by up to a
+    /// quarter.
+    pub fn p99_time(&self) -> Duration {
+        self.p99_time
+    }
+}
+
+// The statistics of a pool, by fingerprint
+#[derive(Default)]
+pub(crate) struct StatsTable {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Default)]
+struct Entry {
+    calls: u64,
+    errors: u64,
+    // Calls that ran on a connection
+    timed: u64,
+    total_time: Duration,
+    histogram: Histogram,
+}
+
+impl StatsTable {
+    // Records a call of `sql` that ran on a connection for `time`, if it got
+    // one
+    pub(crate) fn record(&self, sql: &str, time: Option<Duration>, failed: bool) {
+        let fingerprint = fingerprint(sql);
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(fingerprint).or_default();
+
+        entry.calls += 1;
+
+        if failed {
+            entry.errors += 1;
+        }
+
+        if let Some(time) = time {
+            entry.timed += 1;
+            entry.total_time += time;
+            entry.histogram.record(time);
+        }
+    }
+
+    // The statistics of every fingerprint, by descending total time
+    pub(crate) fn snapshot(&self) -> Vec<QueryStats> {
+        let entries = self.entries.lock().unwrap();
+
+        let mut stats: Vec<_> = entries
+            .iter()
+            .map(|(fingerprint, entry)| QueryStats {
+                fingerprint: fingerprint.clone(),
+                calls: entry.calls,
+                errors: entry.errors,
+                total_time: entry.total_time,
+                mean_time: match entry.timed {
+                    0 => Duration::ZERO,
+                    timed => Duration::from_nanos(
+                        (entry.total_time.as_nanos() / u128::from(timed)) as u64,
+                    ),
+                },
+                p99_time: entry.histogram.percentile(entry.timed, 0.99),
+            })
+            .collect();
+
+        stats.sort_by_key(|stats| Reverse(stats.total_time));
+        stats
+    }
+}
+
+// Counts times in buckets of microseconds growing by a quarter power of two,
+// so that percentiles are known within a quarter however long queries take
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    max: Duration,
+}
+
+impl Histogram {
+    fn record(&mut self, time: Duration) {
+        let bucket = bucket(time.as_micros().min(u64::MAX as u128) as u64);
+
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+
+        self.buckets[bucket] += 1;
+        self.max = self.max.max(time);
+    }
+
+    // The upper bound of the bucket holding the `quantile` of `count` times
+    fn percentile(&self, count: u64, quantile: f64) -> Duration {
+        let rank = (count as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+
+        for (bucket, n) in self.buckets.iter().enumerate() {
+            seen += n;
+
+            if seen >= rank.max(1) {
+                return upper_bound(bucket).min(self.max);
+            }
+        }
+
+        self.max
+    }
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros == 0 {
+        return 0;
+    }
+
+    let exponent = 63 - micros.leading_zeros();
+
+    // The two bits following the leading one
+    let quarter = if exponent >= 2 {
+        (micros >> (exponent - 2)) & 3
+    } else {
+        (micros << (2 - exponent)) & 3
+    };
+
+    1 + exponent as usize * 4 + quarter as usize
+}
+
+fn upper_bound(bucket: usize) -> Duration {
+    if bucket == 0 {
+        return Duration::from_micros(1);
+    }
+
+    let exponent = (bucket - 1) / 4;
+    let quarter = (bucket - 1) % 4;
+
+    // `(4 + quarter + 1) / 4 * 2^exponent`, computed in 128 bits as the last
+    // buckets go past `u64::MAX`
+    let micros = ((4 + quarter as u128 + 1) << exponent) / 4;
+
+    Duration::from_micros(micros.min(u64::MAX as u128) as u64)
+}
@@ -0,0 +1,142 @@
+use crate::{
+    context::{self, QueryTarget},
+    intercept::OperationKind,
+    trace::{Rows, Timer},
+    AsyncError, AsyncResult,
+};
+use diesel::{result::QueryResult, Connection};
+use futures::stream::Stream;
+use std::{
+    cell::Cell,
+    fmt,
+    future::Future,
+    panic::Location,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::{mpsc, oneshot};
+
+// Rows the producer may run ahead of the consumer before it blocks
+const BUFFER: usize = 64;
+
+/// A stream of rows produced by [`load_stream`](crate::AsyncRunQueryDsl::load_stream)
+/// or `load_cursor_async`.
+///
+/// Rows are sent over a bounded channel by a blocking producer that holds a
+/// connection of its own. The producer waits whenever the consumer falls
+/// behind, and stops and releases its connection once the stream is dropped.
+/// How many rows it holds in memory meanwhile depends on the method: all of
+/// them for `load_stream`, one batch for `load_cursor_async`.
+#[must_use = "streams do nothing unless polled"]
+pub struct QueryStream<'a, U> {
+    // Runs the producer as an operation of the pool, completing with the
+    // number of rows sent once it is done
+    operation: Option<Pin<Box<dyn Future<Output = AsyncResult<usize>> + Send + 'a>>>,
+
+    receiver: mpsc::Receiver<U>,
+
+    // The error the operation failed with, returned after the last row
+    error: Option<AsyncError>,
+}
+
+// The sending half handed to a producer
+pub(crate) struct Sink<U> {
+    sender: mpsc::Sender<U>,
+    sent: Cell<usize>,
+}
+
+impl<U> Sink<U> {
+    // Blocks until there is room for `item`. Returns `false` once the stream
+    // has been dropped, at which point the producer should stop.
+    pub(crate) fn send(&self, item: U) -> bool {
+        let sent = self.sender.blocking_send(item).is_ok();
+        self.sent.set(self.sent.get() + sent as usize);
+
+        sent
+    }
+}
+
+// Starts `produce` on a connection taken from `asc` and streams whatever it
+// sends, instrumented like the other queries. An error returned by `produce`
+// ends the stream.
+pub(crate) fn spawn<'a, Conn, AsyncConn, U, F>(
+    asc: &'a AsyncConn,
+    sql: Option<String>,
+    location: &'static Location<'static>,
+    kind: OperationKind,
+    produce: F,
+) -> QueryStream<'a, U>
+where
+    Conn: 'static + Connection,
+    AsyncConn: QueryTarget<Conn> + Sync + ?Sized,
+    U: Send + 'static,
+    F: FnOnce(&Conn, &Sink<U>) -> QueryResult<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(BUFFER);
+    let timer = Timer::new();
+
+    let job = {
+        let timer = timer.clone();
+
+        async move {
+            let (done, finished) = oneshot::channel();
+
+            asc.run_detached(move |conn| {
+                let sink = Sink {
+                    sender,
+                    sent: Cell::new(0),
+                };
+
+                let result = timer.time(|| produce(conn, &sink));
+                let _ = done.send(result.map(|_| sink.sent.get()));
+            })
+            .await?;
+
+            // Dropped without an answer if the producer panicked
+            match finished.await {
+                Ok(result) => result.map_err(AsyncError::Error),
+                Err(_) => Err(AsyncError::Join),
+            }
+        }
+    };
+
+    let operation = context::instrument(asc, sql, location, kind, timer, job, |rows| {
+        Rows::Returned(*rows)
+    });
+
+    QueryStream {
+        operation: Some(Box::pin(operation)),
+        receiver,
+        error: None,
+    }
+}
+
+impl<'a, U> Stream for QueryStream<'a, U> {
+    type Item = AsyncResult<U>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if let Some(ref mut operation) = this.operation {
+            if let Poll::Ready(result) = operation.as_mut().poll(cx) {
+                this.operation = None;
+                this.error = result.err();
+            }
+        }
+
+        match futures::ready!(this.receiver.poll_recv(cx)) {
+            Some(row) => Poll::Ready(Some(Ok(row))),
+
+            // The producer is done, and the operation about to be
+            None if this.operation.is_some() => Poll::Pending,
+
+            None => Poll::Ready(this.error.take().map(Err)),
+        }
+    }
+}
+
+impl<'a, U> fmt::Debug for QueryStream<'a, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueryStream").finish()
+    }
+}
@@ -0,0 +1,325 @@
+use crate::{
+    comment::Commented,
+    context::{self, RunQuery},
+    intercept::OperationKind,
+    settings::Settings,
+    trace::Rows,
+    AsyncError, QueryFuture,
+};
+use diesel::{
+    backend::Backend,
+    dsl::Limit,
+    query_builder::QueryFragment,
+    query_dsl::{
+        methods::{ExecuteDsl, LimitDsl, LoadQuery},
+        RunQueryDsl,
+    },
+    result::{Error, QueryResult},
+    Connection,
+};
+use std::{panic::Location, time::Duration};
+
+/// Connections that can have the database abort statements running longer
+/// than a given time.
+///
+/// * PostgreSQL uses `SET LOCAL statement_timeout`, running the statement in
+///   a transaction (or a savepoint when one is already open).
+/// * MySQL sets `max_execution_time` for the session while the statement
+///   runs. MySQL only applies it to read-only `SELECT` statements.
+///
+/// SQLite is not supported as Diesel does not expose the handle needed to
+/// install a progress handler.
+pub trait StatementTimeout: Connection {
+    /// Runs `f` with statements limited to `timeout`.
+    fn with_statement_timeout<R, F>(&self, timeout: Duration, f: F) -> QueryResult<R>
+    where
+        F: FnOnce() -> QueryResult<R>;
+
+    /// Whether `err` is the database reporting that the limit was reached.
+    fn is_statement_timeout(err: &Error) -> bool;
+}
+
+// A timeout of zero turns the limit off on both backends
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+fn millis(timeout: Duration) -> u128 {
+    timeout.as_millis().max(1)
+}
+
+#[cfg(feature = "postgres")]
+impl StatementTimeout for diesel::pg::PgConnection {
+    fn with_statement_timeout<R, F>(&self, timeout: Duration, f: F) -> QueryResult<R>
+    where
+        F: FnOnce() -> QueryResult<R>,
+    {
+        use diesel::{
+            connection::{SimpleConnection, TransactionManager},
+            dsl::sql,
+            sql_types::Text,
+        };
+
+        let nested =
+            TransactionManager::<Self>::get_transaction_depth(self.transaction_manager()) > 0;
+
+        self.transaction(|| {
+            // Releasing a savepoint keeps `SET LOCAL`, so put back the
+            // setting of the enclosing transaction afterwards
+            let previous = if nested {
+                Some(
+                    diesel::select(sql::<Text>("current_setting('statement_timeout')"))
+                        .get_result::<String>(self)?,
+                )
+            } else {
+                None
+            };
+
+            self.batch_execute(&format!(
+                "SET LOCAL statement_timeout = {}",
+                millis(timeout)
+            ))?;
+
+            let value = f()?;
+
+            if let Some(previous) = previous {
+                diesel::select(
+                    sql::<Text>("set_config('statement_timeout', ")
+                        .bind::<Text, _>(previous)
+                        .sql(", true)"),
+                )
+                .execute(self)?;
+            }
+
+            Ok(value)
+        })
+    }
+
+    fn is_statement_timeout(err: &Error) -> bool {
+        match *err {
+            Error::DatabaseError(_, ref info) => info
+                .message()
+                .contains("canceling statement due to statement timeout"),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl StatementTimeout for diesel::mysql::MysqlConnection {
+    fn with_statement_timeout<R, F>(&self, timeout: Duration, f: F) -> QueryResult<R>
+    where
+        F: FnOnce() -> QueryResult<R>,
+    {
+        use diesel::connection::SimpleConnection;
+
+        self.batch_execute(&format!(
+            "SET @tokio_diesel_max_execution_time = @@max_execution_time, max_execution_time = {}",
+            millis(timeout)
+        ))?;
+
+        let result = f();
+        let restored =
+            self.batch_execute("SET max_execution_time = @tokio_diesel_max_execution_time");
+
+        let value = result?;
+        restored?;
+
+        Ok(value)
+    }
+
+    fn is_statement_timeout(err: &Error) -> bool {
+        match *err {
+            Error::DatabaseError(_, ref info) => info
+                .message()
+                .contains("maximum statement execution time exceeded"),
+            _ => false,
+        }
+    }
+}
+
+/// Adds [`with_timeout`](TimeoutDsl::with_timeout) to queries.
+pub trait TimeoutDsl: Sized {
+    /// Limits how long the database may spend running this query. A query
+    /// that runs out of time fails with [`AsyncError::Timeout`].
+    ///
+    /// The returned query is run with the same methods as
+    /// [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl), on connections that
+    /// implement [`StatementTimeout`].
+    fn with_timeout(self, timeout: Duration) -> WithTimeout<Self> {
+        WithTimeout {
+            query: self,
+            timeout,
+        }
+    }
+}
+
+// Diesel implements `RunQueryDsl` for its queries and statements whatever the
+// connection, so asking for it with a connection no query is written for
+// picks them out, including `sql_query`, without adding `with_timeout` to
+// expressions.
+impl<T> TimeoutDsl for T where T: RunQueryDsl<AnyConnection> {}
+
+struct AnyConnection;
+
+/// A query limited to a server-side timeout, created by
+/// [`with_timeout`](TimeoutDsl::with_timeout).
+#[derive(Debug, Clone, Copy)]
+#[must_use = "queries are not executed unless run"]
+pub struct WithTimeout<T> {
+    query: T,
+    timeout: Duration,
+}
+
+impl<T> WithTimeout<T>
+where
+    T: Send,
+{
+    #[track_caller]
+    pub fn execute_async<'a, Conn, AsyncConn>(self, asc: &'a AsyncConn) -> QueryFuture<'a, usize>
+    where
+        Conn: 'static + StatementTimeout,
+        <Conn::Backend as Backend>::QueryBuilder: Default,
+        AsyncConn: RunQuery<Conn, T, usize> + Sync,
+        T: ExecuteDsl<Conn> + RunQueryDsl<Conn> + QueryFragment<Conn::Backend> + 'a,
+    {
+        self.run(
+            asc,
+            Location::caller(),
+            OperationKind::Execute,
+            |query, comment, conn| match comment {
+                Some(comment) => ExecuteDsl::execute(Commented::new(query, comment), conn),
+                None => query.execute(conn),
+            },
+            |rows| Rows::Affected(*rows),
+        )
+    }
+
+    #[track_caller]
+    pub fn load_async<'a, U, Conn, AsyncConn>(self, asc: &'a AsyncConn) -> QueryFuture<'a, Vec<U>>
+    where
+        U: Send + 'a,
+        Conn: 'static + StatementTimeout,
+        <Conn::Backend as Backend>::QueryBuilder: Default,
+        AsyncConn: RunQuery<Conn, T, Vec<U>> + Sync,
+        T: LoadQuery<Conn, U> + RunQueryDsl<Conn> + QueryFragment<Conn::Backend> + 'a,
+    {
+        self.run(
+            asc,
+            Location::caller(),
+            OperationKind::Load,
+            |query, _, conn| query.load(conn),
+            |rows| Rows::Returned(rows.len()),
+        )
+    }
+
+    #[track_caller]
+    pub fn get_result_async<'a, U, Conn, AsyncConn>(self, asc: &'a AsyncConn) -> QueryFuture<'a, U>
+    where
+        U: Send + 'a,
+        Conn: 'static + StatementTimeout,
+        <Conn::Backend as Backend>::QueryBuilder: Default,
+        AsyncConn: RunQuery<Conn, T, U> + Sync,
+        T: LoadQuery<Conn, U> + RunQueryDsl<Conn> + QueryFragment<Conn::Backend> + 'a,
+    {
+        self.run(
+            asc,
+            Location::caller(),
+            OperationKind::GetResult,
+            |query, _, conn| query.get_result(conn),
+            |_| Rows::Returned(1),
+        )
+    }
+
+    #[track_caller]
+    pub fn get_results_async<'a, U, Conn, AsyncConn>(
+        self,
+        asc: &'a AsyncConn,
+    ) -> QueryFuture<'a, Vec<U>>
+    where
+        U: Send + 'a,
+        Conn: 'static + StatementTimeout,
+        <Conn::Backend as Backend>::QueryBuilder: Default,
+        AsyncConn: RunQuery<Conn, T, Vec<U>> + Sync,
+        T: LoadQuery<Conn, U> + RunQueryDsl<Conn> + QueryFragment<Conn::Backend> + 'a,
+    {
+        self.run(
+            asc,
+            Location::caller(),
+            OperationKind::GetResults,
+            |query, _, conn| query.get_results(conn),
+            |rows| Rows::Returned(rows.len()),
+        )
+    }
+
+    #[track_caller]
+    pub fn first_async<'a, U, Conn, AsyncConn>(self, asc: &'a AsyncConn) -> QueryFuture<'a, U>
+    where
+        U: Send + 'a,
+        Conn: 'static + StatementTimeout,
+        <Conn::Backend as Backend>::QueryBuilder: Default,
+        AsyncConn: RunQuery<Conn, T, U> + Sync,
+        T: LimitDsl + RunQueryDsl<Conn> + QueryFragment<Conn::Backend> + 'a,
+        Limit<T>: LoadQuery<Conn, U>,
+    {
+        self.run(
+            asc,
+            Location::caller(),
+            OperationKind::First,
+            |query, _, conn| query.first(conn),
+            |_| Rows::Returned(1),
+        )
+    }
+
+    // Runs the query like `AsyncRunQueryDsl` does, inside the timeout. `f`
+    // is given the comment to append, rendered on the calling task.
+    fn run<'a, R, Conn, AsyncConn, F>(
+        self,
+        asc: &'a AsyncConn,
+        location: &'static Location<'static>,
+        kind: OperationKind,
+        f: F,
+        rows: fn(&R) -> Rows,
+    ) -> QueryFuture<'a, R>
+    where
+        R: Send + 'a,
+        Conn: 'static + StatementTimeout,
+        <Conn::Backend as Backend>::QueryBuilder: Default,
+        AsyncConn: RunQuery<Conn, T, R> + Sync,
+        T: QueryFragment<Conn::Backend> + 'a,
+        F: FnOnce(T, Option<String>, &Conn) -> QueryResult<R> + Send + 'static,
+    {
+        let WithTimeout { query, timeout } = self;
+        let comment = asc.settings().and_then(Settings::comment);
+
+        Box::pin(async move {
+            context::run(
+                asc,
+                query,
+                location,
+                kind,
+                move |query, conn| conn.with_statement_timeout(timeout, || f(query, comment, conn)),
+                rows,
+            )
+            .await
+            .map_err(|err| timed_out::<Conn>(err, timeout))
+        })
+    }
+}
+
+// Turns the database reporting that the limit was reached into `Timeout`,
+// keeping the context of the query
+fn timed_out<Conn>(err: AsyncError, timeout: Duration) -> AsyncError
+where
+    Conn: StatementTimeout,
+{
+    match err {
+        AsyncError::Error(ref err) if Conn::is_statement_timeout(err) => {
+            AsyncError::Timeout(timeout)
+        }
+
+        AsyncError::Query { error, context } => AsyncError::Query {
+            error: Box::new(timed_out::<Conn>(*error, timeout)),
+            context,
+        },
+
+        err => err,
+    }
+}
@@ -0,0 +1,374 @@
+use crate::{
+    budget::{self, Exceeded},
+    intercept::{self, Interception, OperationKind},
+    AsyncError, AsyncResult,
+};
+use diesel::backend::Backend;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Times a job run against a connection, from when the operation was
+// requested until the job started (waiting for a connection) and then
+// finished (blocking)
+#[derive(Clone)]
+pub(crate) struct Timer {
+    called: Instant,
+    times: Arc<Mutex<Times>>,
+}
+
+#[derive(Default)]
+struct Times {
+    started: Option<Instant>,
+    finished: Option<Instant>,
+}
+
+impl Timer {
+    pub(crate) fn new() -> Self {
+        Timer {
+            called: Instant::now(),
+            times: Arc::default(),
+        }
+    }
+
+    // Runs `f`, the job, on the connection's thread
+    pub(crate) fn time<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.times.lock().unwrap().started = Some(Instant::now());
+        let result = f();
+        self.times.lock().unwrap().finished = Some(Instant::now());
+
+        result
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.called.elapsed()
+    }
+
+    // `None` if the job never started, e.g. because no connection was free
+    pub(crate) fn pool_wait(&self) -> Option<Duration> {
+        let started = self.times.lock().unwrap().started?;
+
+        Some(started - self.called)
+    }
+
+    // `None` unless the job ran to completion
+    pub(crate) fn blocking(&self) -> Option<Duration> {
+        let times = self.times.lock().unwrap();
+
+        Some(times.finished? - times.started?)
+    }
+}
+
+// Rows reported by a finished query
+#[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+pub(crate) enum Rows {
+    Affected(usize),
+    Returned(usize),
+}
+
+// Instruments one operation: a span following the OpenTelemetry database
+// conventions with the `tracing` feature, and the metrics of its pool with
+// the `metrics` feature
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+
+    #[cfg(feature = "metrics")]
+    operation: &'static str,
+
+    // Metrics are only recorded for operations of a named pool
+    #[cfg(feature = "metrics")]
+    pool: Option<Arc<str>>,
+
+    interception: Option<Interception>,
+
+    // Set when the operation would go over an enforced query budget
+    over_budget: Option<Exceeded>,
+}
+
+tokio::task_local! {
+    // Set while an operation runs, so that a query run with
+    // `AsyncRunQueryDsl` is not intercepted, metered or counted again as the
+    // `run` it makes
+    static NESTED: ();
+}
+
+// Whether an operation is already running on the current task
+pub(crate) fn nested() -> bool {
+    NESTED.try_with(|_| ()).is_ok()
+}
+
+impl Span {
+    // `operation` names the span, `statement` is the SQL if known. An
+    // operation nested in another one runs in the span of the outer one.
+    #[cfg_attr(
+        not(feature = "tracing"),
+        allow(unused_variables, clippy::extra_unused_type_parameters)
+    )]
+    pub(crate) fn new<DB>(operation: &'static str, statement: Option<&str>) -> Self
+    where
+        DB: Backend + 'static,
+    {
+        Span {
+            #[cfg(feature = "tracing")]
+            span: if nested() {
+                tracing::Span::none()
+            } else {
+                span::<DB>(operation, statement)
+            },
+
+            #[cfg(feature = "metrics")]
+            operation,
+
+            #[cfg(feature = "metrics")]
+            pool: None,
+
+            interception: None,
+
+            over_budget: if nested() {
+                None
+            } else {
+                budget::charge(statement).err()
+            },
+        }
+    }
+
+    // Records metrics for the operation labelled with the name of the pool,
+    // and the size of the pool if `state` knows it
+    #[cfg_attr(not(feature = "metrics"), allow(unused_mut, unused_variables))]
+    pub(crate) fn pool<S>(mut self, name: &str, state: S) -> Self
+    where
+        S: FnOnce() -> Option<r2d2::State>,
+    {
+        #[cfg(feature = "metrics")]
+        if !nested() {
+            let name: Arc<str> = name.into();
+
+            if let Some(state) = state() {
+                metrics::gauge!("tokio_diesel_connections", "pool" => name.clone())
+                    .set(state.connections);
+                metrics::gauge!("tokio_diesel_idle_connections", "pool" => name.clone())
+                    .set(state.idle_connections);
+            }
+
+            self.pool = Some(name);
+        }
+
+        self
+    }
+
+    // Labels the metrics of the operation with `kind` instead of the name of
+    // the span
+    #[cfg_attr(not(feature = "metrics"), allow(unused_mut, unused_variables))]
+    pub(crate) fn kind(mut self, kind: OperationKind) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            self.operation = kind.label();
+        }
+
+        self
+    }
+
+    // Runs the operation between the hooks of the pool's interceptors
+    pub(crate) fn intercept(mut self, interception: Option<Interception>) -> Self {
+        self.interception = interception;
+        self
+    }
+
+    // Runs `future` in the span, recording the times of `timer` and the
+    // error, if any, once it completes
+    pub(crate) async fn instrument<R, F>(&self, timer: &Timer, future: F) -> AsyncResult<R>
+    where
+        F: Future<Output = AsyncResult<R>>,
+    {
+        let future = NESTED.scope((), async {
+            match self.over_budget {
+                Some(ref exceeded) => Err(AsyncError::OverBudget(exceeded.clone())),
+                None => intercept::run(self.interception.as_ref(), timer, future).await,
+            }
+        });
+
+        #[cfg(feature = "tracing")]
+        let result = tracing::Instrument::instrument(future, self.span.clone()).await;
+
+        #[cfg(not(feature = "tracing"))]
+        let result = future.await;
+
+        self.record(timer, result.as_ref().err());
+
+        result
+    }
+
+    // Runs the future returned by `job` in the span. `job` is given the timer
+    // to time the closure it hands to the connection.
+    pub(crate) async fn run<R, F, Job>(self, job: Job) -> AsyncResult<R>
+    where
+        F: Future<Output = AsyncResult<R>>,
+        Job: FnOnce(Timer) -> F,
+    {
+        let timer = Timer::new();
+
+        self.instrument(&timer, job(timer.clone())).await
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn record_rows(&self, rows: Rows) {
+        #[cfg(feature = "tracing")]
+        match rows {
+            Rows::Affected(rows) => self.span.record("db.rows_affected", rows as u64),
+            Rows::Returned(rows) => self.span.record("db.response.returned_rows", rows as u64),
+        };
+    }
+
+    #[cfg_attr(
+        not(any(feature = "tracing", feature = "metrics")),
+        allow(unused_variables)
+    )]
+    fn record(&self, timer: &Timer, error: Option<&AsyncError>) {
+        #[cfg(feature = "tracing")]
+        {
+            if let Some(pool_wait) = timer.pool_wait() {
+                self.span
+                    .record("db.pool_wait_ms", pool_wait.as_millis() as u64);
+            }
+
+            if let Some(blocking) = timer.blocking() {
+                self.span
+                    .record("db.blocking_ms", blocking.as_millis() as u64);
+            }
+
+            if let Some(err) = error {
+                self.span.record("otel.status_code", "ERROR");
+                self.span
+                    .record("otel.status_message", tracing::field::display(err));
+
+                tracing::debug!(parent: &self.span, error = %err, "database operation failed");
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            let pool = match self.pool {
+                Some(ref pool) => pool,
+                None => return,
+            };
+
+            let labels = [
+                ("pool", pool.to_string()),
+                ("operation", self.operation.into()),
+            ];
+
+            if let Some(pool_wait) = timer.pool_wait() {
+                metrics::histogram!("tokio_diesel_checkout_wait_seconds", &labels)
+                    .record(pool_wait);
+            }
+
+            if let Some(blocking) = timer.blocking() {
+                metrics::histogram!("tokio_diesel_blocking_seconds", &labels).record(blocking);
+            }
+
+            metrics::histogram!("tokio_diesel_query_duration_seconds", &labels)
+                .record(timer.elapsed());
+
+            if let Some(err) = error {
+                metrics::counter!(
+                    "tokio_diesel_errors_total",
+                    "pool" => pool.clone(),
+                    "operation" => self.operation,
+                    "kind" => error_kind(err),
+                )
+                .increment(1);
+            }
+        }
+    }
+}
+
+// Records how long `get_async` waited for a connection
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_checkout(pool: &str, wait: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!(
+        "tokio_diesel_checkout_wait_seconds",
+        "pool" => pool.to_string(),
+        "operation" => "get",
+    )
+    .record(wait);
+}
+
+#[cfg(feature = "tracing")]
+fn span<DB>(operation: &str, statement: Option<&str>) -> tracing::Span
+where
+    DB: Backend + 'static,
+{
+    use tracing::field::Empty;
+
+    // The SQL keyword, e.g. `SELECT`, rather than the name of the method
+    let keyword = statement
+        .and_then(|statement| statement.split_whitespace().next())
+        .map(|keyword| keyword.to_ascii_uppercase());
+
+    tracing::info_span!(
+        "db",
+        otel.name = operation,
+        otel.kind = "client",
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+        db.system = system::<DB>(),
+        db.statement = statement,
+        db.operation = keyword.as_deref(),
+        db.rows_affected = Empty,
+        db.response.returned_rows = Empty,
+        db.pool_wait_ms = Empty,
+        db.blocking_ms = Empty,
+    )
+}
+
+// The `db.system` of the backend
+#[cfg(feature = "tracing")]
+fn system<DB>() -> &'static str
+where
+    DB: Backend + 'static,
+{
+    use std::any::TypeId;
+
+    let systems: &[(TypeId, &str)] = &[
+        #[cfg(feature = "postgres")]
+        (TypeId::of::<diesel::pg::Pg>(), "postgresql"),
+        #[cfg(feature = "mysql")]
+        (TypeId::of::<diesel::mysql::Mysql>(), "mysql"),
+        #[cfg(feature = "sqlite")]
+        (TypeId::of::<diesel::sqlite::Sqlite>(), "sqlite"),
+    ];
+
+    systems
+        .iter()
+        .find(|&&(id, _)| id == TypeId::of::<DB>())
+        .map_or("other_sql", |&(_, system)| system)
+}
+
+// The `kind` label of the error counter
+#[cfg(feature = "metrics")]
+fn error_kind(err: &AsyncError) -> &'static str {
+    match *err.without_context() {
+        AsyncError::Checkout(_) => "checkout",
+        AsyncError::CheckoutTimeout(_) => "checkout_timeout",
+        AsyncError::PoolClosed => "pool_closed",
+        AsyncError::Error(diesel::result::Error::NotFound) => "not_found",
+        AsyncError::Error(_) => "database",
+        AsyncError::RetriesExhausted { .. } => "retries_exhausted",
+        AsyncError::Timeout(_) => "timeout",
+        AsyncError::Cancelled => "cancelled",
+        AsyncError::Panicked(_) => "panicked",
+        AsyncError::Broken => "broken",
+        AsyncError::Join => "join",
+        AsyncError::Runtime(_) => "runtime",
+        AsyncError::Rejected(_) => "rejected",
+        AsyncError::OverBudget(_) => "over_budget",
+        AsyncError::Query { .. } => "query",
+    }
+}
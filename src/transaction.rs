@@ -0,0 +1,335 @@
+use crate::{
+    connection::AsyncPooledConnection,
+    context::{QueryTarget, RunQuery},
+    manager::Managed,
+    options::{BeginTransaction, TransactionOptions},
+    settings::Settings,
+    AsyncConnection, AsyncError, AsyncResult, AsyncSimpleConnection, QueryFuture,
+};
+use async_trait::async_trait;
+use diesel::{
+    connection::TransactionManager,
+    result::{Error, QueryResult},
+    Connection,
+};
+use std::{fmt, future::Future, sync::Arc};
+
+/// An open transaction on a pooled connection.
+///
+/// Queries run through this handle with [`AsyncRunQueryDsl`](crate::AsyncRunQueryDsl)
+/// or [`AsyncConnection`] take part in the transaction, and other async work
+/// can be awaited between them. The transaction is rolled back if the handle
+/// is dropped without calling [`commit`](AsyncTransaction::commit). When the
+/// rollback can't be run, such as on a drop outside a Tokio runtime, the
+/// connection is closed instead of being returned to the pool.
+pub struct AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    conn: Arc<AsyncPooledConnection<Conn>>,
+
+    // Transaction depth of the connection while this transaction is open
+    depth: u32,
+
+    // Set once committed or rolled back
+    finished: bool,
+}
+
+impl<Conn> AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) async fn begin(conn: Arc<AsyncPooledConnection<Conn>>) -> AsyncResult<Self> {
+        Self::start(conn, |conn| {
+            conn.transaction_manager().begin_transaction(conn)
+        })
+        .await
+    }
+
+    pub(crate) async fn begin_with(
+        conn: Arc<AsyncPooledConnection<Conn>>,
+        options: TransactionOptions,
+    ) -> AsyncResult<Self>
+    where
+        Conn: BeginTransaction,
+    {
+        Self::start(conn, move |conn| conn.begin_transaction_with(&options)).await
+    }
+
+    async fn start<F>(conn: Arc<AsyncPooledConnection<Conn>>, begin: F) -> AsyncResult<Self>
+    where
+        F: FnOnce(&Conn) -> QueryResult<()> + Send + 'static,
+    {
+        let depth = conn
+            .with_conn(|conn| {
+                begin(conn).map_err(AsyncError::Error)?;
+
+                Ok(conn.transaction_manager().get_transaction_depth())
+            })
+            .await?;
+
+        Ok(AsyncTransaction {
+            conn,
+            depth,
+            finished: false,
+        })
+    }
+
+    /// Creates a savepoint inside this transaction, returned as a nested
+    /// transaction with its own commit and rollback.
+    ///
+    /// Committing or rolling back a transaction also rolls back any savepoint
+    /// inside it that is still open.
+    pub async fn savepoint(&self) -> AsyncResult<AsyncTransaction<Conn>> {
+        let depth = self.depth;
+        Self::start(self.conn.clone(), move |conn| {
+            let manager = conn.transaction_manager();
+
+            if manager.get_transaction_depth() != depth {
+                return Err(inactive(manager.get_transaction_depth() < depth));
+            }
+
+            manager.begin_transaction(conn)
+        })
+        .await
+    }
+
+    /// Runs `f` inside a savepoint, releasing it when `f` succeeds and
+    /// rolling it back when `f` fails.
+    ///
+    /// `f` receives a handle to the savepoint that can be used to run queries
+    /// (or create further savepoints) but that does not end the savepoint when
+    /// dropped.
+    pub async fn in_savepoint<R, F, Fut>(&self, f: F) -> AsyncResult<R>
+    where
+        F: FnOnce(AsyncTransaction<Conn>) -> Fut,
+        Fut: Future<Output = AsyncResult<R>>,
+    {
+        let savepoint = self.savepoint().await?;
+        let handle = AsyncTransaction {
+            conn: savepoint.conn.clone(),
+            depth: savepoint.depth,
+            finished: true,
+        };
+
+        match f(handle).await {
+            Ok(value) => {
+                savepoint.commit().await?;
+                Ok(value)
+            }
+
+            Err(err) => {
+                savepoint.rollback().await?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Commits the transaction.
+    pub async fn commit(mut self) -> AsyncResult<()> {
+        let depth = self.depth;
+        self.finished = true;
+        self.conn
+            .with_conn(move |conn| {
+                let result = unwind(conn, depth).and_then(|_| {
+                    let manager = conn.transaction_manager();
+                    if let Err(err) = manager.commit_transaction(&**conn) {
+                        // Leave the connection outside of the transaction either way
+                        if manager.get_transaction_depth() == depth {
+                            let _ = manager.rollback_transaction(&**conn);
+                        }
+
+                        return Err(AsyncError::Error(err));
+                    }
+
+                    Ok(())
+                });
+
+                settle(conn, depth);
+                result
+            })
+            .await
+    }
+
+    /// Rolls back the transaction.
+    pub async fn rollback(mut self) -> AsyncResult<()> {
+        let depth = self.depth;
+        self.finished = true;
+        self.conn
+            .with_conn(move |conn| {
+                let result = unwind(conn, depth).and_then(|_| {
+                    conn.transaction_manager()
+                        .rollback_transaction(&**conn)
+                        .map_err(AsyncError::Error)
+                });
+
+                settle(conn, depth);
+                result
+            })
+            .await
+    }
+}
+
+// Ensures the connection is at `depth`, rolling back anything nested inside
+// this transaction that was left open.
+fn unwind<Conn>(conn: &Managed<Conn>, depth: u32) -> AsyncResult<()>
+where
+    Conn: Connection,
+{
+    let manager = conn.transaction_manager();
+
+    if manager.get_transaction_depth() < depth {
+        return Err(AsyncError::Error(inactive(true)));
+    }
+
+    while manager.get_transaction_depth() > depth {
+        manager
+            .rollback_transaction(&**conn)
+            .map_err(AsyncError::Error)?;
+    }
+
+    Ok(())
+}
+
+// Called once the transaction at `depth` has ended. If committing or rolling
+// back failed the connection is still inside it, so it is marked broken for
+// the pool to drop it rather than hand it out mid-transaction.
+fn settle<Conn>(conn: &Managed<Conn>, depth: u32)
+where
+    Conn: Connection,
+{
+    if conn.transaction_manager().get_transaction_depth() >= depth {
+        conn.mark_broken();
+    }
+}
+
+fn inactive(finished: bool) -> Error {
+    let message = if finished {
+        "transaction is no longer active"
+    } else {
+        "a savepoint inside this transaction is still open"
+    };
+
+    Error::QueryBuilderError(message.into())
+}
+
+impl<Conn> Drop for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        let depth = self.depth;
+        self.conn.spawn(move |conn| {
+            if unwind(conn, depth).is_ok() {
+                let _ = conn.transaction_manager().rollback_transaction(&**conn);
+            }
+
+            settle(conn, depth);
+        });
+    }
+}
+
+impl<Conn> fmt::Debug for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncTransaction")
+            .field("depth", &self.depth)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncSimpleConnection<Conn> for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn batch_execute_async(&self, query: &str) -> AsyncResult<()> {
+        self.conn.batch_execute_async(query).await
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncConnection<Conn> for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    #[inline]
+    async fn run<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send,
+    {
+        self.conn.run(f).await
+    }
+
+    // Nests inside this transaction using a savepoint
+    #[inline]
+    async fn transaction<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send,
+    {
+        self.conn.transaction(f).await
+    }
+
+    #[inline]
+    async fn run_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.conn.run_owned(f).await
+    }
+
+    #[inline]
+    async fn transaction_owned<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        Func: FnOnce(&Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.conn.transaction_owned(f).await
+    }
+}
+
+impl<Conn> QueryTarget<Conn> for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn settings(&self) -> Option<&Settings<Conn>> {
+        self.conn.settings()
+    }
+
+    fn pool_state(&self) -> Option<r2d2::State> {
+        None
+    }
+
+    fn run_detached<'a, F>(&'a self, f: F) -> QueryFuture<'a, ()>
+    where
+        F: FnOnce(&Conn) + Send + 'static,
+    {
+        self.conn.run_detached(f)
+    }
+}
+
+impl<Conn, T, R> RunQuery<Conn, T, R> for AsyncTransaction<Conn>
+where
+    Conn: 'static + Connection,
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    fn run_query<'a, F>(&'a self, query: T, f: F) -> QueryFuture<'a, R>
+    where
+        T: 'a,
+        R: 'a,
+        F: FnOnce(T, &Conn) -> QueryResult<R> + Send + 'static,
+    {
+        self.conn.run_owned(move |conn| f(query, conn))
+    }
+}
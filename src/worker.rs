@@ -0,0 +1,220 @@
+use crate::{
+    cancel::{self, Canceller},
+    executor,
+    manager::{Managed, Manager},
+    AsyncError, AsyncResult,
+};
+use diesel::{
+    r2d2::{Pool, PooledConnection},
+    Connection,
+};
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+
+type Slot<Conn> = Option<PooledConnection<Manager<Conn>>>;
+
+type WorkerJob<Conn> = Box<dyn FnOnce(&Pool<Manager<Conn>>, &mut Slot<Conn>) + Send>;
+
+// One OS thread per pool slot. A thread checks out a connection for the first
+// job of a lease and keeps it pinned until the lease ends, so the connection
+// stays on one thread while in use and at most `max_size` threads are ever
+// blocked on the database. Between leases the connection is back in the
+// r2d2 pool, which checks, expires and replaces it as configured.
+pub(crate) struct Workers<Conn>
+where
+    Conn: 'static + Connection,
+{
+    idle: Mutex<Vec<mpsc::Sender<WorkerJob<Conn>>>>,
+    canceller: Option<Arc<Canceller<Conn>>>,
+}
+
+impl<Conn> Workers<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) fn new(pool: &Pool<Manager<Conn>>, canceller: Option<Arc<Canceller<Conn>>>) -> Self {
+        let size = pool.max_size() as usize;
+        let mut idle = Vec::with_capacity(size);
+
+        for index in 0..size {
+            let (sender, receiver) = mpsc::channel::<WorkerJob<Conn>>();
+            let pool = pool.clone();
+
+            thread::Builder::new()
+                .name(format!("tokio-diesel-conn-{}", index))
+                .spawn(move || {
+                    let mut slot = None;
+
+                    // Exits once the owning pool (and so the sender) is dropped.
+                    // A connection that broke stays in the slot, failing the
+                    // rest of the jobs of the lease, until the lease ends.
+                    for job in receiver {
+                        job(&pool, &mut slot);
+                    }
+                })
+                .expect("failed to spawn connection worker");
+
+            idle.push(sender);
+        }
+
+        Workers {
+            idle: Mutex::new(idle),
+            canceller,
+        }
+    }
+
+    // There is one pool permit per worker so holding a permit guarantees
+    // that an idle worker is available
+    pub(crate) fn lease(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> Arc<Lease<Conn>> {
+        let sender = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a permit guarantees an idle worker");
+
+        Arc::new(Lease {
+            workers: self.clone(),
+            sender: Mutex::new(Some(sender)),
+            permit: Some(permit),
+        })
+    }
+}
+
+// Exclusive use of one worker (and so one connection). Every job holds on to
+// the lease so the worker only goes back to the idle list once the lease has
+// been dropped and no job submitted through it is still queued or running.
+pub(crate) struct Lease<Conn>
+where
+    Conn: 'static + Connection,
+{
+    workers: Arc<Workers<Conn>>,
+    sender: Mutex<Option<mpsc::Sender<WorkerJob<Conn>>>>,
+
+    // Released once the connection is back in the pool
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<Conn> Lease<Conn>
+where
+    Conn: 'static + Connection,
+{
+    pub(crate) async fn run<R, F>(self: &Arc<Self>, f: F) -> AsyncResult<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Managed<Conn>) -> AsyncResult<R> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let guard = cancel::Guard::new(self.workers.canceller.as_ref());
+        let flight = guard.flight();
+
+        self.submit(move |pool, slot| {
+            if slot.is_none() {
+                match pool.get() {
+                    Ok(conn) => *slot = Some(conn),
+                    Err(err) => {
+                        let _ = tx.send(Err(AsyncError::Checkout(err)));
+                        return;
+                    }
+                }
+            }
+
+            let conn = slot.as_ref().unwrap();
+            let _ = tx.send(conn.catch_unwind(|| cancel::run(flight, conn, f)));
+        });
+
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => Err(AsyncError::Join),
+        }
+    }
+
+    // Runs `f` on the calling thread once the jobs queued before it are
+    // done. The worker lends its connection for `f` to borrow and waits for
+    // it to come back, so jobs on the lease still run one at a time.
+    pub(crate) fn run_here<R, F>(self: &Arc<Self>, f: F) -> AsyncResult<R>
+    where
+        F: FnOnce(&Conn) -> AsyncResult<R>,
+    {
+        let (lend, lent) = mpsc::channel();
+        let (give_back, returned) = mpsc::channel();
+
+        self.submit(move |pool, slot| {
+            let conn = match slot.take().map_or_else(|| pool.get(), Ok) {
+                Ok(conn) => conn,
+                Err(err) => {
+                    let _ = lend.send(Err(AsyncError::Checkout(err)));
+                    return;
+                }
+            };
+
+            if lend.send(Ok(conn)).is_ok() {
+                *slot = returned.recv().ok();
+            }
+        });
+
+        executor::run_here(|| {
+            let conn = lent.recv().map_err(|_| AsyncError::Join)??;
+            let result = conn.catch_unwind(|| f(&conn));
+            let _ = give_back.send(conn);
+
+            result
+        })
+    }
+
+    // Queues `f` on the worker without waiting for it to run. Does nothing
+    // if the worker has not checked out a connection.
+    pub(crate) fn spawn<F>(self: &Arc<Self>, f: F)
+    where
+        F: FnOnce(&Managed<Conn>) + Send + 'static,
+    {
+        self.submit(move |_, slot| {
+            if let Some(ref conn) = *slot {
+                let _ = conn.catch_unwind(|| {
+                    f(conn);
+                    Ok(())
+                });
+            }
+        });
+    }
+
+    fn submit<F>(self: &Arc<Self>, job: F)
+    where
+        F: FnOnce(&Pool<Manager<Conn>>, &mut Slot<Conn>) + Send + 'static,
+    {
+        let lease = self.clone();
+
+        self.sender
+            .lock()
+            .unwrap()
+            .as_ref()
+            .expect("sender is only taken on drop")
+            .send(Box::new(move |pool, slot| {
+                job(pool, slot);
+                drop(lease);
+            }))
+            .expect("connection worker exited");
+    }
+}
+
+impl<Conn> Drop for Lease<Conn>
+where
+    Conn: 'static + Connection,
+{
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.get_mut().unwrap().take() {
+            // Queued behind the jobs of the lease and ahead of those of the
+            // next one
+            let permit = self.permit.take();
+            let _ = sender.send(Box::new(move |_, slot| {
+                slot.take();
+                drop(permit);
+            }));
+
+            self.workers.idle.lock().unwrap().push(sender);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
 
@@ -41,3 +43,1480 @@ async fn test_db_ops() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_current_thread_runtime() -> Result<(), Box<dyn Error>> {
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let pool = Pool::builder().build(manager)?;
+
+    let one: i32 = diesel::select(1.into_sql::<diesel::sql_types::Integer>())
+        .get_result_async(&pool)
+        .await?;
+
+    assert_eq!(one, 1);
+
+    // Queries move off the runtime's only thread, which keeps running tasks
+    let (slept, ticked) = tokio::join!(
+        sql_query("SELECT pg_sleep(0.3)").execute_async(&pool),
+        async {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            std::time::Instant::now()
+        }
+    );
+
+    slept?;
+    assert!(ticked.elapsed() > std::time::Duration::from_millis(200));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_borrowed_binds() -> Result<(), Box<dyn Error>> {
+    use diesel::sql_types::Text;
+
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let pool = Pool::builder().build(manager)?;
+    let name = String::from("borrowed");
+
+    let select = || diesel::select(name.as_str().into_sql::<Text>());
+    let echoed: String = pool.run(|conn| select().get_result(conn)).await?;
+    assert_eq!(echoed, name);
+
+    let echoed: String = pool.transaction(|conn| select().get_result(conn)).await?;
+    assert_eq!(echoed, name);
+
+    // Whichever way the pool runs its queries
+    let builders = vec![
+        AsyncPool::builder().executor(ThreadPool::new(1)),
+        AsyncPool::builder().dedicated_threads(),
+    ];
+
+    for builder in builders {
+        let pool = builder.build(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+        let conn = pool.get_async().await?;
+
+        let select = || diesel::select(name.as_str().into_sql::<Text>());
+        let echoed: String = pool.run(|conn| select().get_result(conn)).await?;
+        assert_eq!(echoed, name);
+
+        let echoed: String = conn.transaction(|conn| select().get_result(conn)).await?;
+        assert_eq!(echoed, name);
+    }
+
+    Ok(())
+}
+
+// Generic over every type of connection queries can run on
+async fn select_one<C>(conn: &C) -> AsyncResult<i32>
+where
+    C: Sync + AsyncConnection<PgConnection> + QueryTarget<PgConnection>,
+    C: RunQuery<PgConnection, diesel::expression::SqlLiteral<diesel::sql_types::Integer>, i32>,
+{
+    diesel::dsl::sql::<diesel::sql_types::Integer>("SELECT 1")
+        .get_result_async(conn)
+        .await
+}
+
+#[tokio::test]
+async fn test_generic_connection() -> Result<(), Box<dyn Error>> {
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    assert_eq!(select_one(&Pool::new(manager)?).await?, 1);
+
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+    assert_eq!(select_one(&pool).await?, 1);
+    assert_eq!(select_one(&pool.get_async().await?).await?, 1);
+    assert_eq!(select_one(&pool.begin().await?).await?, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_executors() -> Result<(), Box<dyn Error>> {
+    let executors: Vec<Box<dyn Fn() -> AsyncPoolBuilder<PgConnection>>> = vec![
+        Box::new(|| AsyncPool::builder().executor(SpawnBlocking)),
+        Box::new(|| AsyncPool::builder().executor(ThreadPool::new(2))),
+        Box::new(|| AsyncPool::builder().executor(Inline)),
+        Box::new(|| AsyncPool::builder().dedicated_threads()),
+    ];
+
+    for builder in executors {
+        let pool = builder()
+            .max_size(2)
+            .build(ConnectionManager::new("postgres://postgres@localhost"))?;
+
+        let one: i32 = diesel::select(1.into_sql::<diesel::sql_types::Integer>())
+            .get_result_async(&pool)
+            .await?;
+
+        assert_eq!(one, 1);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_checkout() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::builder()
+        .max_size(1)
+        .connection_timeout(std::time::Duration::from_millis(100))
+        .build(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+
+    // Queue behind a slow query without blocking the (only) runtime thread
+    let (slow, fast) = tokio::join!(
+        sql_query("SELECT pg_sleep(0.5)").execute_async(&pool),
+        async {
+            tokio::task::yield_now().await;
+            sql_query("SELECT 1").execute_async(&pool).await
+        }
+    );
+
+    assert!(slow.is_ok());
+    assert!(matches!(
+        fast.unwrap_err().without_context(),
+        AsyncError::CheckoutTimeout(_)
+    ));
+
+    // The connection is handed back once the slow query finishes
+    sql_query("SELECT 1").execute_async(&pool).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_held_connection() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    let conn = pool.get_async().await?;
+
+    // Session state survives between queries
+    conn.batch_execute_async("CREATE TEMPORARY TABLE held (id integer)")
+        .await?;
+    sql_query("INSERT INTO held VALUES (1), (2)")
+        .execute_async(&conn)
+        .await?;
+
+    let count: i64 = diesel::select(diesel::dsl::sql::<diesel::sql_types::BigInt>(
+        "(SELECT count(*) FROM held)",
+    ))
+    .get_result_async(&conn)
+    .await?;
+
+    assert_eq!(count, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dedicated_threads_return_connections() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::builder()
+        .max_size(2)
+        .dedicated_threads()
+        .build(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+
+    // Workers give connections back to r2d2 once they are done with them
+    let all_idle = || async {
+        for _ in 0..100 {
+            let state = pool.state();
+            if state.idle_connections == state.connections {
+                return true;
+            }
+
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        false
+    };
+
+    sql_query("SELECT 1").execute_async(&pool).await?;
+    assert!(all_idle().await);
+
+    let conn = pool.get_async().await?;
+    sql_query("SELECT 1").execute_async(&conn).await?;
+    let state = pool.state();
+    assert!(state.idle_connections < state.connections);
+
+    drop(conn);
+    assert!(all_idle().await);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_async_transaction() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    let _ = sql_query(include_str!("./create_users.sql"))
+        .execute_async(&pool)
+        .await;
+
+    let rolled_back = Uuid::new_v4();
+    let dropped = Uuid::new_v4();
+    let committed = Uuid::new_v4();
+
+    let tx = pool.begin().await?;
+    diesel::insert_into(users::table)
+        .values(users::id.eq(rolled_back))
+        .execute_async(&tx)
+        .await?;
+    tokio::task::yield_now().await;
+    tx.rollback().await?;
+
+    let tx = pool.begin().await?;
+    diesel::insert_into(users::table)
+        .values(users::id.eq(dropped))
+        .execute_async(&tx)
+        .await?;
+    drop(tx);
+
+    let tx = pool.begin().await?;
+    diesel::insert_into(users::table)
+        .values(users::id.eq(committed))
+        .execute_async(&tx)
+        .await?;
+    tx.commit().await?;
+
+    let found: Vec<Uuid> = users::table
+        .select(users::id)
+        .filter(users::id.eq_any(vec![rolled_back, dropped, committed]))
+        .load_async(&pool)
+        .await?;
+
+    assert_eq!(found, vec![committed]);
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transaction_options() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    let options = TransactionOptions::new()
+        .isolation(IsolationLevel::Serializable)
+        .read_only()
+        .deferrable();
+
+    let isolation: String = pool
+        .transaction_with(options, |conn| {
+            diesel::select(diesel::dsl::sql::<diesel::sql_types::Text>(
+                "current_setting('transaction_isolation')",
+            ))
+            .get_result(conn)
+        })
+        .await?;
+
+    assert_eq!(isolation, "serializable");
+
+    let tx = pool.begin_with(options).await?;
+    let write = sql_query("CREATE TEMPORARY TABLE read_only (id integer)")
+        .execute_async(&tx)
+        .await;
+
+    assert!(write.is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_transaction_retry() -> Result<(), Box<dyn Error>> {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    let policy = RetryPolicy::new()
+        .max_attempts(3)
+        .initial_backoff(std::time::Duration::from_millis(1));
+
+    let runs = Arc::new(AtomicU32::new(0));
+    let counter = runs.clone();
+
+    let result: AsyncResult<()> = pool
+        .transaction_with_retry(policy, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+
+            Err(DieselError::DatabaseError(
+                DatabaseErrorKind::SerializationFailure,
+                Box::new("could not serialize access".to_string()),
+            ))
+        })
+        .await;
+
+    assert!(matches!(
+        result,
+        Err(AsyncError::RetriesExhausted { attempts: 3, .. })
+    ));
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_savepoints() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    let _ = sql_query(include_str!("./create_users.sql"))
+        .execute_async(&pool)
+        .await;
+
+    let ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+    let insert = |id: Uuid| diesel::insert_into(users::table).values(users::id.eq(id));
+
+    let tx = pool.begin().await?;
+    insert(ids[0]).execute_async(&tx).await?;
+
+    let savepoint = tx.savepoint().await?;
+    insert(ids[1]).execute_async(&savepoint).await?;
+    savepoint.rollback().await?;
+
+    let savepoint = tx.savepoint().await?;
+    insert(ids[2]).execute_async(&savepoint).await?;
+    savepoint.commit().await?;
+
+    let last = ids[3];
+    let result: AsyncResult<()> = tx
+        .in_savepoint(|savepoint| async move {
+            insert(last).execute_async(&savepoint).await?;
+            Err(AsyncError::Error(
+                diesel::result::Error::RollbackTransaction,
+            ))
+        })
+        .await;
+
+    assert!(result.is_err());
+    tx.commit().await?;
+
+    let mut found: Vec<Uuid> = users::table
+        .select(users::id)
+        .filter(users::id.eq_any(ids.clone()))
+        .load_async(&pool)
+        .await?;
+    found.sort();
+
+    let mut expected = vec![ids[0], ids[2]];
+    expected.sort();
+
+    assert_eq!(found, expected);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failed_rollback_discards_connection() -> Result<(), Box<dyn Error>> {
+    let builders = vec![
+        AsyncPool::builder(),
+        AsyncPool::builder().dedicated_threads(),
+    ];
+
+    for builder in builders {
+        let pool =
+            builder.max_size(1).test_on_check_out(false).build(
+                ConnectionManager::<PgConnection>::new("postgres://postgres@localhost"),
+            )?;
+
+        let backend_pid = || {
+            diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>(
+                "pg_backend_pid()",
+            ))
+        };
+
+        let tx = pool.begin().await?;
+        let before: i32 = backend_pid().get_result_async(&tx).await?;
+
+        // Rolling back a savepoint that no longer exists fails and leaves
+        // the connection inside the savepoint
+        let savepoint = tx.savepoint().await?;
+        savepoint
+            .batch_execute_async("RELEASE SAVEPOINT diesel_savepoint_1")
+            .await?;
+        assert!(savepoint.rollback().await.is_err());
+        drop(tx);
+
+        let after: i32 = backend_pid().get_result_async(&pool).await?;
+        assert_ne!(before, after);
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_load_stream() -> Result<(), Box<dyn Error>> {
+    use futures::StreamExt;
+
+    let builders = vec![
+        AsyncPool::builder(),
+        AsyncPool::builder().dedicated_threads(),
+    ];
+
+    for builder in builders {
+        let pool = builder
+            .max_size(1)
+            .build(ConnectionManager::<PgConnection>::new(
+                "postgres://postgres@localhost",
+            ))?;
+
+        let series = || {
+            diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>(
+                "generate_series(1, 1000)",
+            ))
+        };
+
+        let rows: Vec<i32> = series()
+            .load_stream::<i32>(&pool)
+            .map(|row| row.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(rows, (1..=1000).collect::<Vec<_>>());
+
+        // Dropping a stream part way through hands the connection back
+        let mut stream = series().load_stream::<i32>(&pool);
+        assert_eq!(stream.next().await.transpose()?, Some(1));
+        drop(stream);
+
+        let one: i32 = diesel::select(1.into_sql::<diesel::sql_types::Integer>())
+            .get_result_async(&pool)
+            .await?;
+
+        assert_eq!(one, 1);
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_load_cursor() -> Result<(), Box<dyn Error>> {
+    use futures::StreamExt;
+
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    // Bind parameters are passed through to the cursor's query
+    let series = || {
+        diesel::select(
+            diesel::dsl::sql::<diesel::sql_types::Integer>("generate_series(1, ")
+                .bind::<diesel::sql_types::Integer, _>(1000)
+                .sql(")"),
+        )
+    };
+
+    let rows: Vec<i32> = series()
+        .load_cursor_async::<i32>(&pool, 64)
+        .map(|row| row.unwrap())
+        .collect()
+        .await;
+
+    assert_eq!(rows, (1..=1000).collect::<Vec<_>>());
+
+    // Cursors can be read one after the other within a transaction, even if
+    // the first one is dropped early
+    let tx = pool.begin().await?;
+
+    let mut stream = series().load_cursor_async::<i32>(&tx, 10);
+    assert_eq!(stream.next().await.transpose()?, Some(1));
+    drop(stream);
+
+    let count = series().load_cursor_async::<i32>(&tx, 300).count().await;
+    assert_eq!(count, 1000);
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_transaction_dropped_outside_runtime() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::builder()
+        .executor(SpawnBlocking)
+        .max_size(1)
+        .min_idle(Some(0))
+        .build(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let tx = runtime.block_on(pool.begin())?;
+    drop(runtime);
+
+    // Without a runtime to roll back on, the connection is discarded rather
+    // than returned to the pool inside the transaction
+    drop(tx);
+    assert_eq!(pool.state().connections, 0);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(sql_query("SELECT 1").execute_async(&pool))?;
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_cancel_on_drop() -> Result<(), Box<dyn Error>> {
+    use std::time::{Duration, Instant};
+
+    // Queries run with `Auto` block the task awaiting them, so they can't be
+    // interrupted by a timeout
+    let builders = vec![
+        AsyncPool::builder().executor(SpawnBlocking),
+        AsyncPool::builder().dedicated_threads(),
+    ];
+
+    for builder in builders {
+        let pool =
+            builder
+                .max_size(1)
+                .cancel_on_drop()
+                .build(ConnectionManager::<PgConnection>::new(
+                    "postgres://postgres@localhost",
+                ))?;
+
+        let started = Instant::now();
+        let sleep = tokio::time::timeout(
+            Duration::from_millis(200),
+            sql_query("SELECT pg_sleep(10)").execute_async(&pool),
+        )
+        .await;
+
+        assert!(sleep.is_err());
+
+        // The connection is free again well before the sleep would end
+        sql_query("SELECT 1").execute_async(&pool).await?;
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_statement_timeout() -> Result<(), Box<dyn Error>> {
+    use std::time::Duration;
+
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    let slow = sql_query("SELECT pg_sleep(5)")
+        .with_timeout(Duration::from_millis(100))
+        .execute_async(&pool)
+        .await;
+
+    assert!(matches!(
+        slow.unwrap_err().without_context(),
+        AsyncError::Timeout(_)
+    ));
+
+    // Inside a transaction the timeout only applies to the one statement
+    let tx = pool.begin().await?;
+
+    let one: i32 = diesel::select(1.into_sql::<diesel::sql_types::Integer>())
+        .with_timeout(Duration::from_millis(100))
+        .get_result_async(&tx)
+        .await?;
+
+    assert_eq!(one, 1);
+
+    sql_query("SELECT pg_sleep(0.2)").execute_async(&tx).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_panic_discards_connection() -> Result<(), Box<dyn Error>> {
+    let builders = vec![
+        AsyncPool::builder(),
+        AsyncPool::builder().dedicated_threads(),
+    ];
+
+    for builder in builders {
+        let pool = builder
+            .max_size(1)
+            .build(ConnectionManager::<PgConnection>::new(
+                "postgres://postgres@localhost",
+            ))?;
+
+        let backend_pid = || {
+            diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>(
+                "pg_backend_pid()",
+            ))
+        };
+
+        let before: i32 = backend_pid().get_result_async(&pool).await?;
+
+        let result: AsyncResult<()> = pool.transaction(|_| panic!("boom")).await;
+
+        match result {
+            Err(AsyncError::Panicked(payload)) => assert_eq!(payload.message(), Some("boom")),
+            other => panic!("expected a panic, got {:?}", other),
+        }
+
+        // The connection was left mid-transaction so a new one is used
+        let after: i32 = backend_pid().get_result_async(&pool).await?;
+        assert_ne!(before, after);
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_panic_breaks_held_connection() -> Result<(), Box<dyn Error>> {
+    let builders = vec![
+        AsyncPool::builder(),
+        AsyncPool::builder().dedicated_threads(),
+    ];
+
+    let _ = sql_query(include_str!("./create_users.sql"))
+        .execute_async(&AsyncPool::new(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?)
+        .await;
+
+    for builder in builders {
+        let pool = builder
+            .max_size(1)
+            .build(ConnectionManager::<PgConnection>::new(
+                "postgres://postgres@localhost",
+            ))?;
+
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        let insert = |id: Uuid| diesel::insert_into(users::table).values(users::id.eq(id));
+
+        let tx = pool.begin().await?;
+        insert(ids[0]).execute_async(&tx).await?;
+
+        let result: AsyncResult<()> = tx.run(|_| panic!("boom")).await;
+        assert!(matches!(result, Err(AsyncError::Panicked(_))));
+
+        // Nothing else runs on the connection, in or out of the transaction
+        let err = insert(ids[1]).execute_async(&tx).await.unwrap_err();
+        assert!(matches!(err.without_context(), AsyncError::Broken));
+        assert!(matches!(tx.rollback().await, Err(AsyncError::Broken)));
+
+        let found: i64 = users::table
+            .filter(users::id.eq_any(ids.to_vec()))
+            .count()
+            .get_result_async(&pool)
+            .await?;
+        assert_eq!(found, 0);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_variants() -> Result<(), Box<dyn Error>> {
+    let pool =
+        AsyncPool::builder().executor(BlockInPlace).build(
+            ConnectionManager::<PgConnection>::new("postgres://postgres@localhost"),
+        )?;
+
+    // `block_in_place` is not available on a current_thread runtime
+    let result = sql_query("SELECT 1").execute_async(&pool).await;
+    assert!(matches!(
+        result.unwrap_err().without_context(),
+        AsyncError::Runtime(_)
+    ));
+
+    pool.close();
+    assert!(pool.is_closed());
+
+    let result = pool.get_async().await;
+    assert!(matches!(result, Err(AsyncError::PoolClosed)));
+
+    // Errors of queries are only wrapped with the `query-context` feature
+    #[cfg(not(feature = "query-context"))]
+    {
+        let pool = Pool::new(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+        let result = diesel::select(1.into_sql::<diesel::sql_types::Integer>())
+            .filter(false.into_sql::<diesel::sql_types::Bool>())
+            .get_result_async::<i32>(&pool)
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(AsyncError::Error(diesel::result::Error::NotFound))
+        ));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_classification() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    let conn = pool.get_async().await?;
+    conn.batch_execute_async(
+        "CREATE TEMPORARY TABLE parents (id integer CONSTRAINT parents_pk PRIMARY KEY);
+         CREATE TEMPORARY TABLE children (parent integer REFERENCES parents);
+         INSERT INTO parents VALUES (1);",
+    )
+    .await?;
+
+    let err = sql_query("INSERT INTO parents VALUES (1)")
+        .execute_async(&conn)
+        .await
+        .unwrap_err();
+
+    assert!(err.is_unique_violation());
+    assert!(!err.is_foreign_key_violation());
+    assert!(!err.is_retryable());
+    assert_eq!(err.constraint_name(), Some("parents_pk"));
+
+    let err = sql_query("INSERT INTO children VALUES (2)")
+        .execute_async(&conn)
+        .await
+        .unwrap_err();
+
+    assert!(err.is_foreign_key_violation());
+
+    let err = diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>("1"))
+        .filter(diesel::dsl::sql::<diesel::sql_types::Bool>("false"))
+        .get_result_async::<i32>(&conn)
+        .await
+        .unwrap_err();
+
+    assert!(err.is_not_found());
+    assert!(!err.is_connection_lost());
+
+    // Giving up on a lock another connection holds
+    sql_query("SELECT pg_advisory_lock(42)")
+        .execute_async(&conn)
+        .await?;
+
+    let other = pool.get_async().await?;
+    other
+        .batch_execute_async("SET lock_timeout = '50ms'")
+        .await?;
+
+    let err = sql_query("SELECT pg_advisory_lock(42)")
+        .execute_async(&other)
+        .await
+        .unwrap_err();
+
+    assert!(err.is_retryable());
+    assert!(!err.is_connection_lost());
+
+    Ok(())
+}
+
+#[cfg(feature = "query-context")]
+#[tokio::test]
+async fn test_query_context() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    let err = diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>("1 / 0"))
+        .filter(
+            diesel::dsl::sql::<diesel::sql_types::Bool>("")
+                .bind::<diesel::sql_types::Bool, _>(true),
+        )
+        .get_result_async::<i32>(&pool)
+        .await
+        .unwrap_err();
+
+    let context = err.context().expect("query errors carry a context");
+    assert!(context.location().file().ends_with("integration_test.rs"));
+    assert!(context.pool_wait().is_some());
+    assert!(context.elapsed() >= context.pool_wait().unwrap());
+    assert!(matches!(err.without_context(), AsyncError::Error(_)));
+
+    let sql = context.sql().unwrap();
+    assert!(sql.starts_with("SELECT 1 / 0"));
+    assert!(sql.contains("$1"));
+    assert!(err.to_string().contains(sql));
+
+    // Queries run with a timeout carry one too
+    #[cfg(feature = "postgres")]
+    {
+        let err = diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>("1 / 0"))
+            .with_timeout(std::time::Duration::from_secs(5))
+            .get_result_async::<i32, _, _>(&pool)
+            .await
+            .unwrap_err();
+
+        let context = err.context().expect("query errors carry a context");
+        assert!(context.sql().unwrap().starts_with("SELECT 1 / 0"));
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_lost_connection_evicted() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::builder()
+        .max_size(1)
+        .test_on_check_out(false)
+        .build(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+
+    let backend_pid = || {
+        diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>(
+            "pg_backend_pid()",
+        ))
+    };
+    let before: i32 = backend_pid().get_result_async(&pool).await?;
+
+    let err = sql_query("SELECT pg_terminate_backend(pg_backend_pid())")
+        .execute_async(&pool)
+        .await
+        .unwrap_err();
+    assert!(err.is_connection_lost());
+
+    // Without checking connections out, only the error tells the pool the
+    // connection is gone
+    let after: i32 = backend_pid().get_result_async(&pool).await?;
+    assert_ne!(before, after);
+
+    Ok(())
+}
+
+// Records the fields of every span
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct Spans(std::sync::Arc<std::sync::Mutex<Vec<Fields>>>);
+
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct Fields(std::collections::HashMap<String, String>);
+
+#[cfg(feature = "tracing")]
+impl Spans {
+    fn find(&self, name: &str) -> std::collections::HashMap<String, String> {
+        let spans = self.0.lock().unwrap();
+        let span = spans
+            .iter()
+            .find(|span| span.0.get("otel.name").map(String::as_str) == Some(name));
+
+        span.cloned().expect("no such span").0
+    }
+
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for Fields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        let value = format!("{:?}", value).trim_matches('"').to_string();
+        self.0.insert(field.name().into(), value);
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for Spans {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut spans = self.0.lock().unwrap();
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        spans.push(fields);
+
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        let mut spans = self.0.lock().unwrap();
+        values.record(&mut spans[span.into_u64() as usize - 1]);
+    }
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+    fn event(&self, _: &tracing::Event<'_>) {}
+    fn enter(&self, _: &tracing::span::Id) {}
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn test_tracing() -> Result<(), Box<dyn Error>> {
+    let spans = Spans::default();
+    let _default = tracing::subscriber::set_default(spans.clone());
+
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    pool.batch_execute_async("CREATE TEMPORARY TABLE traced (id integer)")
+        .await?;
+
+    let before = spans.len();
+    let rows = sql_query("SELECT 1 AS one UNION ALL SELECT 2")
+        .execute_async(&pool)
+        .await?;
+    assert_eq!(rows, 2);
+
+    // The connection is run inside the query's span rather than one of its own
+    assert_eq!(spans.len(), before + 1);
+
+    let batch = spans.find("batch_execute");
+    assert_eq!(batch["db.system"], "postgresql");
+    assert_eq!(
+        batch["db.statement"],
+        "CREATE TEMPORARY TABLE traced (id integer)"
+    );
+    assert!(batch.contains_key("db.pool_wait_ms"));
+    assert!(batch.contains_key("db.blocking_ms"));
+
+    let query = spans.find("query");
+    assert_eq!(query["db.operation"], "SELECT");
+    assert_eq!(query["db.rows_affected"], "2");
+
+    Ok(())
+}
+
+// Records the key of every metric emitted
+#[cfg(feature = "metrics")]
+#[derive(Clone, Default)]
+struct Keys(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+#[cfg(feature = "metrics")]
+impl Keys {
+    fn push(&self, key: &metrics::Key) {
+        let labels: Vec<_> = key
+            .labels()
+            .map(|label| format!("{}={}", label.key(), label.value()))
+            .collect();
+
+        let key = format!("{}{{{}}}", key.name(), labels.join(","));
+        self.0.lock().unwrap().push(key);
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.0.lock().unwrap().iter().any(|k| k == key)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl metrics::Recorder for Keys {
+    fn describe_counter(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+    fn describe_gauge(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+    fn describe_histogram(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+
+    fn register_counter(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Counter {
+        self.push(key);
+        metrics::Counter::noop()
+    }
+
+    fn register_gauge(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Gauge {
+        self.push(key);
+        metrics::Gauge::noop()
+    }
+
+    fn register_histogram(
+        &self,
+        key: &metrics::Key,
+        _: &metrics::Metadata<'_>,
+    ) -> metrics::Histogram {
+        self.push(key);
+        metrics::Histogram::noop()
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_metrics() -> Result<(), Box<dyn Error>> {
+    let keys = Keys::default();
+    metrics::set_global_recorder(keys.clone())?;
+
+    let pool =
+        AsyncPool::builder()
+            .name("metered")
+            .build(ConnectionManager::<PgConnection>::new(
+                "postgres://postgres@localhost",
+            ))?;
+
+    assert_eq!(pool.name(), "metered");
+
+    sql_query("SELECT 1").execute_async(&pool).await?;
+    sql_query("SELECT 1 / 0")
+        .execute_async(&pool)
+        .await
+        .unwrap_err();
+    pool.get_async().await?;
+
+    // Queries are labelled with the method they were run with, not as the
+    // `run` they make
+    assert!(keys.contains("tokio_diesel_checkout_wait_seconds{pool=metered,operation=execute}"));
+    assert!(keys.contains("tokio_diesel_blocking_seconds{pool=metered,operation=execute}"));
+    assert!(keys.contains("tokio_diesel_query_duration_seconds{pool=metered,operation=execute}"));
+    assert!(
+        keys.contains("tokio_diesel_errors_total{pool=metered,operation=execute,kind=database}")
+    );
+    assert!(!keys.contains("tokio_diesel_query_duration_seconds{pool=metered,operation=run}"));
+
+    pool.run(|conn| conn.execute("SELECT 1")).await?;
+    assert!(keys.contains("tokio_diesel_query_duration_seconds{pool=metered,operation=run}"));
+
+    assert!(keys.contains("tokio_diesel_connections{pool=metered}"));
+    assert!(keys.contains("tokio_diesel_idle_connections{pool=metered}"));
+    assert!(keys.contains("tokio_diesel_checkout_wait_seconds{pool=metered,operation=get}"));
+
+    Ok(())
+}
+
+// Keeps the messages of every log record of the slow query log
+#[cfg(feature = "postgres")]
+struct SlowQueries(std::sync::Mutex<Vec<String>>);
+
+#[cfg(feature = "postgres")]
+impl log::Log for SlowQueries {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.target() == "tokio_diesel::slow_query"
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(feature = "postgres")]
+static SLOW_QUERIES: SlowQueries = SlowQueries(std::sync::Mutex::new(Vec::new()));
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn test_slow_query_log() -> Result<(), Box<dyn Error>> {
+    log::set_logger(&SLOW_QUERIES).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let pool = AsyncPool::builder()
+        .slow_query_threshold(std::time::Duration::from_millis(50))
+        .explain_slow_queries()
+        .build(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+
+    sql_query("SELECT 1").execute_async(&pool).await?;
+
+    diesel::select(
+        diesel::dsl::sql::<diesel::sql_types::Bool>("pg_sleep(")
+            .bind::<diesel::sql_types::Double, _>(0.1)
+            .sql(") IS NULL"),
+    )
+    .get_result_async::<bool>(&pool)
+    .await?;
+
+    // The plan is looked up on another thread
+    let message = loop {
+        if let Some(message) = SLOW_QUERIES.0.lock().unwrap().first() {
+            break message.clone();
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+
+    assert!(message.contains("SELECT pg_sleep($1) IS NULL"));
+    assert!(message.contains("integration_test.rs"));
+    assert!(message.contains("\"Plan\""), "{}", message);
+    assert_eq!(SLOW_QUERIES.0.lock().unwrap().len(), 1);
+
+    // The same statement is not explained again
+    diesel::select(
+        diesel::dsl::sql::<diesel::sql_types::Bool>("pg_sleep(")
+            .bind::<diesel::sql_types::Double, _>(0.2)
+            .sql(") IS NULL"),
+    )
+    .get_result_async::<bool>(&pool)
+    .await?;
+
+    let messages = SLOW_QUERIES.0.lock().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert!(messages[1].contains("SELECT pg_sleep($1) IS NULL"));
+    assert!(!messages[1].contains("plan:"));
+
+    Ok(())
+}
+
+// Rejects queries on the `secrets` table and records every operation
+#[derive(Clone, Default)]
+struct Audit(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+impl QueryInterceptor for Audit {
+    fn before(&self, operation: &Operation<'_>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match operation.sql() {
+            Some(sql) if sql.contains("secrets") => Err("access to secrets denied".into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn after(&self, operation: &Operation<'_>, outcome: &Outcome<'_>) {
+        let entry = format!(
+            "{:?} {} {}",
+            operation.kind(),
+            operation.sql().unwrap_or("-"),
+            if outcome.is_ok() { "ok" } else { "failed" }
+        );
+
+        self.0.lock().unwrap().push(entry);
+    }
+}
+
+#[tokio::test]
+async fn test_interceptor() -> Result<(), Box<dyn Error>> {
+    let audit = Audit::default();
+    let pool =
+        AsyncPool::builder().interceptor(audit.clone()).build(
+            ConnectionManager::<PgConnection>::new("postgres://postgres@localhost"),
+        )?;
+
+    pool.batch_execute_async("SET application_name = 'audited'")
+        .await?;
+
+    sql_query("SELECT 1").execute_async(&pool).await?;
+    pool.run(|conn| conn.execute("SELECT 1")).await?;
+
+    let err = sql_query("SELECT * FROM secrets")
+        .execute_async(&pool)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(err.without_context(), AsyncError::Rejected(_)));
+    assert_eq!(
+        err.without_context().to_string(),
+        "rejected by an interceptor: access to secrets denied"
+    );
+
+    // A query is seen once, not again as the `run` it makes
+    assert_eq!(
+        *audit.0.lock().unwrap(),
+        [
+            "BatchExecute SET application_name = 'audited' ok",
+            "Execute SELECT 1 ok",
+            "Run - ok",
+            "Execute SELECT * FROM secrets failed",
+        ]
+    );
+
+    // Streams are seen once they end, and can be rejected too
+    #[cfg(feature = "postgres")]
+    {
+        use diesel::{dsl::sql, sql_types::Integer};
+        use futures::StreamExt;
+
+        audit.0.lock().unwrap().clear();
+
+        let rows: Vec<AsyncResult<i32>> = sql::<Integer>("SELECT 1")
+            .load_stream(&pool)
+            .collect()
+            .await;
+        assert!(matches!(rows[..], [Ok(1)]));
+
+        let rows: Vec<AsyncResult<i32>> = sql::<Integer>("SELECT 1")
+            .load_cursor_async(&pool, 10)
+            .collect()
+            .await;
+        assert!(matches!(rows[..], [Ok(1)]));
+
+        let rows: Vec<AsyncResult<i32>> = sql::<Integer>("SELECT 1 FROM secrets")
+            .load_stream(&pool)
+            .collect()
+            .await;
+        assert!(
+            matches!(rows[..], [Err(ref err)] if matches!(err.without_context(), AsyncError::Rejected(_)))
+        );
+
+        sql_query("SELECT 2")
+            .with_timeout(std::time::Duration::from_secs(5))
+            .execute_async(&pool)
+            .await?;
+
+        assert_eq!(
+            *audit.0.lock().unwrap(),
+            [
+                "LoadStream SELECT 1 ok",
+                "LoadCursor SELECT 1 ok",
+                "LoadStream SELECT 1 FROM secrets failed",
+                "Execute SELECT 2 ok",
+            ]
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_sql_comment_format() {
+    let comment = SqlComment::new()
+        .tag("route", "/invoices/{id}")
+        .tag("app", "billing")
+        .tag("note", "it's */ done");
+
+    assert_eq!(
+        comment.to_string(),
+        "/*app='billing',note='it%27s%20%2A%2F%20done',route='%2Finvoices%2F%7Bid%7D'*/"
+    );
+}
+
+#[tokio::test]
+async fn test_sql_commenter() -> Result<(), Box<dyn Error>> {
+    let pool = AsyncPool::builder()
+        .max_size(1)
+        .sql_commenter(|comment| comment.insert("app", "billing"))
+        .build(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+
+    let conn = pool.get_async().await?;
+
+    conn.batch_execute_async("CREATE TEMPORARY TABLE statements (sql text)")
+        .await?;
+
+    let record = "INSERT INTO statements SELECT current_query()";
+
+    sql_query(record).execute_async(&conn).await?;
+
+    SqlComment::new()
+        .tag("route", "/invoices")
+        .scope(async {
+            sql_query(record).execute_async(&conn).await?;
+
+            // The commenter runs last, so it can override the scope
+            SqlComment::new()
+                .tag("app", "reports")
+                .scope(sql_query(record).execute_async(&conn))
+                .await
+        })
+        .await?;
+
+    let statements: Vec<String> =
+        diesel::dsl::sql::<diesel::sql_types::Text>("SELECT sql FROM statements")
+            .load_async(&conn)
+            .await?;
+
+    assert_eq!(
+        statements,
+        [
+            "INSERT INTO statements SELECT current_query() /*app='billing'*/",
+            "INSERT INTO statements SELECT current_query() /*app='billing',route='%2Finvoices'*/",
+            "INSERT INTO statements SELECT current_query() /*app='billing',route='%2Finvoices'*/",
+        ]
+    );
+
+    #[cfg(feature = "postgres")]
+    {
+        use diesel::{dsl::sql, sql_types::Text};
+        use futures::StreamExt;
+
+        sql_query(record)
+            .with_timeout(std::time::Duration::from_secs(5))
+            .execute_async(&conn)
+            .await?;
+
+        let last: String = sql::<Text>("SELECT sql FROM statements OFFSET 3")
+            .get_result_async(&conn)
+            .await?;
+        assert_eq!(last, statements[0]);
+
+        // The comment ends up on the statement declaring the cursor
+        let declared = "SELECT statement FROM pg_cursors WHERE name = 'tokio_diesel_cursor'";
+        let rows: Vec<String> = sql::<Text>(declared)
+            .load_cursor_async(&conn, 10)
+            .map(|row| row.unwrap())
+            .collect()
+            .await;
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].ends_with(&format!("{} /*app='billing'*/", declared)));
+    }
+
+    // Other queries that return rows are left as they are
+    let row: String = diesel::select(diesel::dsl::sql::<diesel::sql_types::Text>(
+        "current_query()",
+    ))
+    .get_result_async(&conn)
+    .await?;
+    assert_eq!(row, "SELECT current_query()");
+
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct Named {
+    #[sql_type = "diesel::sql_types::Integer"]
+    n: i32,
+}
+
+#[tokio::test]
+async fn test_load_by_name() -> Result<(), Box<dyn Error>> {
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let pool = Pool::builder().build(manager)?;
+
+    let rows: Vec<Named> = sql_query("SELECT 1 AS n").load_async(&pool).await?;
+    assert_eq!(rows.iter().map(|row| row.n).collect::<Vec<_>>(), [1]);
+
+    // Whether or not the pool comments its statements
+    let pool = AsyncPool::builder()
+        .sql_commenter(|comment| comment.insert("app", "billing"))
+        .build(ConnectionManager::<PgConnection>::new(
+            "postgres://postgres@localhost",
+        ))?;
+
+    let row: Named = sql_query("SELECT 2 AS n").get_result_async(&pool).await?;
+    assert_eq!(row.n, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_budget() -> Result<(), Box<dyn Error>> {
+    let manager = ConnectionManager::<PgConnection>::new("postgres://postgres@localhost");
+    let pool = AsyncPool::new(manager)?;
+
+    // The same query loading one row after another
+    let err = budget::Budget::new(10)
+        .max_repeats(2)
+        .enforce()
+        .scope(async {
+            for id in 0..3 {
+                sql_query(format!("SELECT {}", id))
+                    .execute_async(&pool)
+                    .await?;
+            }
+
+            Ok::<_, AsyncError>(())
+        })
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        err.without_context(),
+        AsyncError::OverBudget(budget::Exceeded::Repeats { statement, max: 2 })
+            if statement == "SELECT ?"
+    ));
+
+    // Every query counts once towards the total, as do closures
+    let err = budget::Budget::new(2)
+        .enforce()
+        .scope(async {
+            sql_query("SELECT 1").execute_async(&pool).await?;
+            pool.run(|conn| conn.execute("SELECT 1")).await?;
+            pool.transaction(|conn| conn.execute("SELECT 1")).await
+        })
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        err.without_context(),
+        AsyncError::OverBudget(budget::Exceeded::Queries { max: 2 })
+    ));
+
+    // Budgets that are not enforced only log
+    budget::scope(0, sql_query("SELECT 1").execute_async(&pool)).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_stats() -> Result<(), Box<dyn Error>> {
+    let pool =
+        AsyncPool::builder()
+            .track_query_stats()
+            .build(ConnectionManager::<PgConnection>::new(
+                "postgres://postgres@localhost",
+            ))?;
+
+    for sql in [
+        "SELECT 1 WHERE 'a' IN ('a', 'b', 'c')",
+        "SELECT  2 WHERE 'it''s' IN ('d')",
+        "SELECT 3 FROM missing_table",
+    ] {
+        let _ = sql_query(sql).execute_async(&pool).await;
+    }
+
+    diesel::select(
+        diesel::dsl::sql::<diesel::sql_types::Integer>("").bind::<diesel::sql_types::Integer, _>(4),
+    )
+    .get_result_async::<i32>(&pool)
+    .await?;
+
+    let mut stats = pool.query_stats();
+    stats.sort_by(|a, b| a.fingerprint().cmp(b.fingerprint()));
+
+    let summary: Vec<_> = stats
+        .iter()
+        .map(|stats| (stats.fingerprint(), stats.calls(), stats.errors()))
+        .collect();
+
+    assert_eq!(
+        summary,
+        [
+            ("SELECT ?", 1, 0),
+            ("SELECT ? FROM missing_table", 1, 1),
+            ("SELECT ? WHERE ? IN (...)", 2, 0),
+        ]
+    );
+
+    let stats = &stats[2];
+    assert!(stats.total_time() >= stats.mean_time());
+    assert!(stats.p99_time() > std::time::Duration::ZERO);
+    assert!(stats.p99_time() <= stats.total_time());
+
+    // Pools that do not track statistics have none
+    let pool = AsyncPool::new(ConnectionManager::<PgConnection>::new(
+        "postgres://postgres@localhost",
+    ))?;
+
+    sql_query("SELECT 1").execute_async(&pool).await?;
+    assert!(pool.query_stats().is_empty());
+
+    Ok(())
+}